@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS c (
+	id BIGSERIAL PRIMARY KEY,
+	nano_id varchar(255) NOT NULL UNIQUE,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	value BIGINT NOT NULL DEFAULT 0
+);
+
+CREATE TABLE IF NOT EXISTS g (
+	id BIGSERIAL PRIMARY KEY,
+	nano_id varchar(255) NOT NULL UNIQUE,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	updated_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+	value BIGINT NOT NULL DEFAULT 0
+);
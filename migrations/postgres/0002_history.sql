@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS c_history (
+	nano_id varchar(255) NOT NULL,
+	step integer NOT NULL,
+	bucket BIGINT NOT NULL,
+	delta BIGINT NOT NULL DEFAULT 0,
+	value BIGINT NOT NULL DEFAULT 0,
+	PRIMARY KEY (nano_id, step, bucket)
+);
+
+CREATE TABLE IF NOT EXISTS g_history (
+	nano_id varchar(255) NOT NULL,
+	step integer NOT NULL,
+	bucket BIGINT NOT NULL,
+	delta BIGINT NOT NULL DEFAULT 0,
+	value BIGINT NOT NULL DEFAULT 0,
+	PRIMARY KEY (nano_id, step, bucket)
+);
@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS c_owner (
+	nano_id varchar(255) NOT NULL PRIMARY KEY,
+	token_hash char(64) NOT NULL,
+	protected BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE TABLE IF NOT EXISTS g_owner (
+	nano_id varchar(255) NOT NULL PRIMARY KEY,
+	token_hash char(64) NOT NULL,
+	protected BOOLEAN NOT NULL DEFAULT false
+);
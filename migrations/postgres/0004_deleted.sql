@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS c_deleted (
+	nano_id varchar(255) NOT NULL PRIMARY KEY,
+	deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE TABLE IF NOT EXISTS g_deleted (
+	nano_id varchar(255) NOT NULL PRIMARY KEY,
+	deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
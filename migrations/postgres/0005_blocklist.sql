@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS blocked (
+	pattern varchar(255) NOT NULL PRIMARY KEY,
+	prefix BOOLEAN NOT NULL DEFAULT false,
+	created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS c_label (
+	nano_id varchar(255) NOT NULL,
+	key varchar(64) NOT NULL,
+	value varchar(255) NOT NULL,
+	PRIMARY KEY (nano_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS c_label_key_value ON c_label (key, value);
+
+CREATE TABLE IF NOT EXISTS g_label (
+	nano_id varchar(255) NOT NULL,
+	key varchar(64) NOT NULL,
+	value varchar(255) NOT NULL,
+	PRIMARY KEY (nano_id, key)
+);
+
+CREATE INDEX IF NOT EXISTS g_label_key_value ON g_label (key, value);
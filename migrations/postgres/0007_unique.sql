@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS c_sketch (
+	nano_id varchar(255) NOT NULL PRIMARY KEY,
+	sketch BYTEA NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS salts (
+	day BIGINT NOT NULL PRIMARY KEY,
+	salt char(32) NOT NULL
+);
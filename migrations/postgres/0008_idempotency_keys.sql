@@ -0,0 +1,11 @@
+CREATE TABLE IF NOT EXISTS idempotency_keys (
+	key varchar(255) NOT NULL PRIMARY KEY,
+	request text NOT NULL,
+	status integer,
+	location text,
+	content_type text,
+	body BYTEA,
+	created_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idempotency_keys_created_at ON idempotency_keys (created_at);
@@ -0,0 +1,17 @@
+CREATE TABLE IF NOT EXISTS c_history (
+	`nano_id` varchar(12) NOT NULL,
+	`step` integer NOT NULL,
+	`bucket` integer NOT NULL,
+	`delta` BIG INT NOT NULL DEFAULT 0,
+	`value` BIG INT NOT NULL DEFAULT 0,
+	PRIMARY KEY (`nano_id`, `step`, `bucket`)
+);
+
+CREATE TABLE IF NOT EXISTS g_history (
+	`nano_id` varchar(12) NOT NULL,
+	`step` integer NOT NULL,
+	`bucket` integer NOT NULL,
+	`delta` BIG INT NOT NULL DEFAULT 0,
+	`value` BIG INT NOT NULL DEFAULT 0,
+	PRIMARY KEY (`nano_id`, `step`, `bucket`)
+);
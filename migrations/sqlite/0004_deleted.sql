@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS c_deleted (
+	`nano_id` varchar(12) NOT NULL PRIMARY KEY,
+	`deleted_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS g_deleted (
+	`nano_id` varchar(12) NOT NULL PRIMARY KEY,
+	`deleted_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS blocked (
+	`pattern` varchar(255) NOT NULL PRIMARY KEY,
+	`prefix` boolean NOT NULL DEFAULT 0,
+	`created_at` datetime NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,187 @@
+use crate::{
+    blocklist::Blocklist,
+    labels::{self, Labels},
+    storage::{Kind, Record, Storage},
+    token::AdminToken,
+    Counter, CounterLike, Gauge,
+};
+use actix_web::{
+    body::MessageBody,
+    delete,
+    dev::{HttpServiceFactory, ServiceRequest, ServiceResponse},
+    get, middleware, put,
+    web::{self, Data, Path, Query},
+    Error, HttpResponse, Responder,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+pub fn scope() -> impl HttpServiceFactory {
+    web::scope("/_admin")
+        .wrap(middleware::from_fn(require_token))
+        .service(list_counters)
+        .service(get_counter)
+        .service(delete_counter)
+        .service(list_gauges)
+        .service(get_gauge)
+        .service(delete_gauge)
+        .service(list_blocks)
+        .service(put_block)
+        .service(delete_block)
+}
+
+async fn require_token(
+    req: ServiceRequest,
+    next: middleware::Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let allowed = req
+        .app_data::<Data<AdminToken>>()
+        .is_some_and(|admin| admin.matches(req.request()));
+    if !allowed {
+        return Ok(req
+            .into_response(HttpResponse::Forbidden().body(""))
+            .map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
+
+#[derive(Deserialize)]
+struct ListQuery {
+    prefix: Option<String>,
+    label: Option<String>,
+    after: Option<String>,
+    limit: Option<i64>,
+}
+
+impl ListQuery {
+    fn prefix(&self) -> &str {
+        self.prefix.as_deref().unwrap_or("")
+    }
+
+    fn after(&self) -> &str {
+        self.after.as_deref().unwrap_or("")
+    }
+
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+}
+
+async fn list(
+    storage: &dyn Storage,
+    kind: Kind,
+    query: &ListQuery,
+    labels: &Labels,
+) -> Result<Vec<Record>> {
+    storage
+        .list(kind, query.prefix(), labels, query.after(), query.limit())
+        .await
+}
+
+fn as_json<T: Serialize>(result: Result<T>) -> HttpResponse {
+    match result {
+        Ok(body) => HttpResponse::Ok().json(body),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/c")]
+async fn list_counters(query: Query<ListQuery>, storage: Data<dyn Storage>) -> impl Responder {
+    let Some(labels) = labels::select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    as_json(list(storage.get_ref(), Kind::Counter, &query, &labels).await)
+}
+
+#[get("/c/{id}")]
+async fn get_counter(path: Path<(String,)>, storage: Data<dyn Storage>) -> impl Responder {
+    match storage.get(Kind::Counter, &path.0).await {
+        Ok(Some(rec)) => HttpResponse::Ok().json(rec),
+        Ok(None) => Counter::missing(&path.0, storage.get_ref()).await,
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/c/{id}")]
+async fn delete_counter(path: Path<(String,)>, storage: Data<dyn Storage>) -> impl Responder {
+    match storage.delete(Kind::Counter, &path.0).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => Counter::missing(&path.0, storage.get_ref()).await,
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[get("/g")]
+async fn list_gauges(query: Query<ListQuery>, storage: Data<dyn Storage>) -> impl Responder {
+    let Some(labels) = labels::select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    as_json(list(storage.get_ref(), Kind::Gauge, &query, &labels).await)
+}
+
+#[get("/g/{id}")]
+async fn get_gauge(path: Path<(String,)>, storage: Data<dyn Storage>) -> impl Responder {
+    match storage.get(Kind::Gauge, &path.0).await {
+        Ok(Some(rec)) => HttpResponse::Ok().json(rec),
+        Ok(None) => Gauge::missing(&path.0, storage.get_ref()).await,
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/g/{id}")]
+async fn delete_gauge(path: Path<(String,)>, storage: Data<dyn Storage>) -> impl Responder {
+    match storage.delete(Kind::Gauge, &path.0).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => Gauge::missing(&path.0, storage.get_ref()).await,
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[derive(Deserialize)]
+struct BlockQuery {
+    prefix: Option<bool>,
+}
+
+#[get("/blocks")]
+async fn list_blocks(storage: Data<dyn Storage>) -> impl Responder {
+    as_json(storage.blocks().await)
+}
+
+#[put("/blocks/{pattern}")]
+async fn put_block(
+    path: Path<(String,)>,
+    query: Query<BlockQuery>,
+    blocklist: Data<Blocklist>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Counter::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    let prefix = query.prefix.unwrap_or(false);
+    match storage.block(&path.0, prefix).await {
+        Ok(()) => {
+            let _ = blocklist.refresh(storage.get_ref()).await;
+            HttpResponse::NoContent().finish()
+        }
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
+
+#[delete("/blocks/{pattern}")]
+async fn delete_block(
+    path: Path<(String,)>,
+    blocklist: Data<Blocklist>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    match storage.unblock(&path.0).await {
+        Ok(true) => {
+            let _ = blocklist.refresh(storage.get_ref()).await;
+            HttpResponse::NoContent().finish()
+        }
+        Ok(false) => HttpResponse::NotFound().body(""),
+        Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+    }
+}
@@ -0,0 +1,82 @@
+const HEIGHT: usize = 20;
+const PADDING: usize = 10;
+const LABEL_COLOR: &str = "#555";
+const VALUE_COLOR: &str = "#3b5bdb";
+
+// Approximate advance widths for 11px Verdana, which is what most badge
+// renderers use. Good enough to size the boxes without shipping a font.
+fn char_width(c: char) -> usize {
+    match c {
+        '0'..='9' => 7,
+        '-' | ' ' | '(' | ')' | '[' | ']' | 'r' | 't' | 'f' => 5,
+        '.' | ',' | ':' | ';' | '!' | '|' | '\'' | 'i' | 'j' | 'l' | 'I' => 4,
+        'm' | 'w' | 'M' | 'W' => 11,
+        'A'..='Z' | '_' | '#' | '%' | '&' | '@' => 8,
+        _ => 7,
+    }
+}
+
+fn text_width(text: &str) -> usize {
+    text.chars().map(char_width).sum::<usize>() + PADDING
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Renders a flat, shields.io style badge showing `value`, with an optional
+/// `label` drawn in a grey box to its left. `color` is any SVG paint for the
+/// value box, e.g. `green` or `#4c1`.
+pub fn render(label: Option<&str>, value: &str, color: Option<&str>) -> String {
+    let label = label.filter(|l| !l.is_empty());
+    let label_width = label.map(text_width).unwrap_or(0);
+    let value_width = text_width(value);
+    let width = label_width + value_width;
+    let value = escape(value);
+    let (title, label_text) = match label {
+        Some(label) => {
+            let label = escape(label);
+            (
+                format!("{}: {}", label, value),
+                format!(
+                    r##"<text x="{x}" y="15" fill="#010101" fill-opacity=".3">{label}</text><text x="{x}" y="14">{label}</text>"##,
+                    x = label_width as f32 / 2.0,
+                ),
+            )
+        }
+        None => (value.clone(), String::new()),
+    };
+    format!(
+        concat!(
+            r##"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" role="img" aria-label="{title}">"##,
+            r##"<title>{title}</title>"##,
+            r##"<linearGradient id="s" x2="0" y2="100%"><stop offset="0" stop-color="#bbb" stop-opacity=".1"/><stop offset="1" stop-opacity=".1"/></linearGradient>"##,
+            r##"<clipPath id="r"><rect width="{width}" height="{height}" rx="3" fill="#fff"/></clipPath>"##,
+            r##"<g clip-path="url(#r)"><rect width="{label_width}" height="{height}" fill="{label_color}"/><rect x="{label_width}" width="{value_width}" height="{height}" fill="{value_color}"/><rect width="{width}" height="{height}" fill="url(#s)"/></g>"##,
+            r##"<g fill="#fff" text-anchor="middle" font-family="Verdana,Geneva,DejaVu Sans,sans-serif" font-size="11">"##,
+            r##"{label_text}<text x="{value_x}" y="15" fill="#010101" fill-opacity=".3">{value}</text><text x="{value_x}" y="14">{value}</text>"##,
+            r##"</g></svg>"##,
+        ),
+        width = width,
+        height = HEIGHT,
+        title = title,
+        label_width = label_width,
+        value_width = value_width,
+        label_color = LABEL_COLOR,
+        value_color = escape(color.unwrap_or(VALUE_COLOR)),
+        label_text = label_text,
+        value_x = label_width as f32 + value_width as f32 / 2.0,
+        value = value,
+    )
+}
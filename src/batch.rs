@@ -0,0 +1,214 @@
+use crate::{
+    blocklist::Blocklist,
+    ratelimit::RateLimiter,
+    storage::{Kind, Op, Storage},
+    Counter, CounterLike, Gauge,
+};
+use actix_web::{
+    post,
+    web::{Data, Json},
+    HttpRequest, HttpResponse, Responder,
+};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+/// Upper bound on operations in a single batch.
+const MAX_OPS: usize = 100;
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Get,
+    Incr,
+    Decr,
+    Set,
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy)]
+pub enum Type {
+    #[serde(rename = "c")]
+    Counter,
+    #[serde(rename = "g")]
+    Gauge,
+}
+
+impl From<Type> for Kind {
+    fn from(kind: Type) -> Self {
+        match kind {
+            Type::Counter => Kind::Counter,
+            Type::Gauge => Kind::Gauge,
+        }
+    }
+}
+
+impl From<Kind> for Type {
+    fn from(kind: Kind) -> Self {
+        match kind {
+            Kind::Counter => Type::Counter,
+            Kind::Gauge => Type::Gauge,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Item {
+    op: Action,
+    #[serde(rename = "type")]
+    pub kind: Type,
+    pub id: String,
+    by: Option<i64>,
+    value: Option<i64>,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Outcome {
+    Value { value: i64 },
+    Error { error: &'static str },
+}
+
+impl Item {
+    /// Checks the item on its own, turning it into a storage operation.
+    fn op(&self) -> Result<(Kind, Op), &'static str> {
+        let valid = match self.kind {
+            Type::Counter => Counter::valid_id(&self.id),
+            Type::Gauge => Gauge::valid_id(&self.id),
+        };
+        if !valid {
+            return Err("invalid id");
+        }
+        let by = self.by.unwrap_or(1);
+        match (self.kind, self.op) {
+            (Type::Counter, Action::Get) => Ok((Kind::Counter, Op::Get)),
+            (Type::Counter, Action::Incr) if by >= 0 => Ok((Kind::Counter, Op::Increment(by))),
+            (Type::Counter, Action::Incr | Action::Decr) => Err("counters cannot be decremented"),
+            (Type::Counter, Action::Set) => Err("counters cannot be set"),
+            (Type::Gauge, Action::Get) => Ok((Kind::Gauge, Op::Get)),
+            (Type::Gauge, Action::Incr) => Ok((Kind::Gauge, Op::Increment(by))),
+            (Type::Gauge, Action::Decr) => match by.checked_neg() {
+                Some(by) => Ok((Kind::Gauge, Op::Increment(by))),
+                None => Err("value would overflow"),
+            },
+            (Type::Gauge, Action::Set) => match self.value {
+                Some(value) => Ok((Kind::Gauge, Op::Set(value))),
+                None => Err("missing value"),
+            },
+        }
+    }
+}
+
+/// Checks whether `req` may run `item`, turning it into a storage operation.
+/// Changes take a token from the ID's rate limit, and from `client`'s if
+/// given.
+pub async fn check(
+    item: &Item,
+    req: &HttpRequest,
+    client: Option<&str>,
+    blocklist: &Blocklist,
+    limiter: &RateLimiter,
+    storage: &dyn Storage,
+) -> Result<(Kind, Op), &'static str> {
+    let op = item.op()?;
+    if blocklist.is_blocked(&item.id) {
+        return Err("gone");
+    }
+    let deleted = match op {
+        (_, Op::Get) => false,
+        (Kind::Counter, _) => Counter::deleted(&item.id, storage).await,
+        (Kind::Gauge, _) => Gauge::deleted(&item.id, storage).await,
+    };
+    if deleted {
+        return Err("gone");
+    }
+    let may_write = match op {
+        (_, Op::Get) => true,
+        (Kind::Counter, _) => Counter::may_write(&item.id, req, storage).await,
+        (Kind::Gauge, _) => Gauge::may_write(&item.id, req, storage).await,
+    };
+    if !may_write {
+        return Err("forbidden");
+    }
+    if let (Kind::Counter, Op::Increment(_)) = op {
+        if !matches!(storage.unique(&item.id).await, Ok(false)) {
+            return Err("unique counters count visitors");
+        }
+    }
+    if !matches!(op, (_, Op::Get)) && limiter.take(client, Some(&item.id)).is_err() {
+        return Err("rate limited");
+    }
+    Ok(op)
+}
+
+/// Runs a single operation on its own. Unlike a batch of one, this leaves
+/// buffered increments in the write buffer.
+pub async fn run(kind: Kind, id: &str, op: Op, storage: &dyn Storage) -> Result<Option<i64>> {
+    match op {
+        Op::Get => Ok(storage.get(kind, id).await?.map(|record| record.value)),
+        Op::Increment(by) => storage.increment(kind, id, by).await,
+        Op::Set(value) => Ok(Some(storage.set(kind, id, value).await?)),
+    }
+}
+
+/// Turns the value a storage operation came back with into its outcome.
+pub async fn outcome(
+    kind: Kind,
+    id: &str,
+    op: Op,
+    value: Option<i64>,
+    storage: &dyn Storage,
+) -> Outcome {
+    match (value, op) {
+        (Some(value), _) => Outcome::Value { value },
+        (None, Op::Get) => {
+            let deleted = match kind {
+                Kind::Counter => Counter::deleted(id, storage).await,
+                Kind::Gauge => Gauge::deleted(id, storage).await,
+            };
+            Outcome::Error {
+                error: if deleted { "gone" } else { "not found" },
+            }
+        }
+        (None, _) => Outcome::Error {
+            error: "value would overflow",
+        },
+    }
+}
+
+/// Runs a list of get/incr/decr/set operations on counters and gauges in one
+/// transaction, answering with a result or error for each, in order. The
+/// client was already rate limited for the request as a whole.
+#[post("/_batch")]
+async fn post_batch(
+    req: HttpRequest,
+    items: Json<Vec<Item>>,
+    blocklist: Data<Blocklist>,
+    limiter: Data<RateLimiter>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if items.len() > MAX_OPS {
+        return HttpResponse::BadRequest().body(format!("at most {} operations", MAX_OPS));
+    }
+
+    let mut outcomes = Vec::with_capacity(items.len());
+    let mut ops = Vec::new();
+    let mut indices = Vec::new();
+    for (i, item) in items.iter().enumerate() {
+        match check(item, &req, None, &blocklist, &limiter, storage.get_ref()).await {
+            Ok((kind, op)) => {
+                // Filled in once the batch has run.
+                outcomes.push(None);
+                ops.push((kind, item.id.clone(), op));
+                indices.push(i);
+            }
+            Err(error) => outcomes.push(Some(Outcome::Error { error })),
+        }
+    }
+
+    let Ok(values) = storage.batch(&ops).await else {
+        return HttpResponse::InternalServerError().body("");
+    };
+    for ((i, (kind, id, op)), value) in indices.into_iter().zip(ops).zip(values) {
+        outcomes[i] = Some(outcome(kind, &id, op, value, storage.get_ref()).await);
+    }
+    HttpResponse::Ok().json(outcomes.into_iter().flatten().collect::<Vec<_>>())
+}
@@ -0,0 +1,87 @@
+use crate::storage::Storage;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    middleware::Next,
+    web::Data,
+    Error, HttpResponse,
+};
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use std::{collections::HashSet, sync::RwLock};
+
+#[derive(Serialize)]
+pub struct Block {
+    pub pattern: String,
+    pub prefix: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Default)]
+struct Entries {
+    exact: HashSet<String>,
+    prefixes: Vec<String>,
+}
+
+/// In-memory copy of the `blocked` table, so checking an ID doesn't need a
+/// database round-trip on every hit.
+#[derive(Default)]
+pub struct Blocklist(RwLock<Entries>);
+
+impl Blocklist {
+    pub fn is_blocked(&self, id: &str) -> bool {
+        let entries = self.0.read().unwrap();
+        entries.exact.contains(id) || entries.prefixes.iter().any(|p| id.starts_with(p))
+    }
+
+    pub async fn refresh(&self, storage: &dyn Storage) -> Result<()> {
+        let mut entries = Entries::default();
+        for block in storage.blocks().await? {
+            if block.prefix {
+                entries.prefixes.push(block.pattern);
+            } else {
+                entries.exact.insert(block.pattern);
+            }
+        }
+        *self.0.write().unwrap() = entries;
+        Ok(())
+    }
+}
+
+/// Pulls the counter or gauge ID out of a request path such as `/c/ID`,
+/// `/g+/ID.svg` or `/c/ID/history`. Any extension is left on, as IDs may
+/// themselves contain dots.
+pub fn path_id(path: &str) -> Option<String> {
+    let mut segments = path.trim_start_matches('/').split('/');
+    match segments.next()? {
+        "c" | "c+" | "g" | "g+" | "g-" | "g=" => {}
+        _ => return None,
+    }
+    let id = percent_decode_str(segments.next()?).decode_utf8().ok()?;
+    Some(id.into_owned())
+}
+
+/// Middleware that answers `410 Gone` for any request touching a blocked ID,
+/// before it reaches a handler.
+pub async fn reject(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let blocked = match (path_id(req.path()), req.app_data::<Data<Blocklist>>()) {
+        (Some(id), Some(blocklist)) => {
+            blocklist.is_blocked(&id)
+                || id
+                    .rsplit_once('.')
+                    .is_some_and(|(id, _)| blocklist.is_blocked(id))
+        }
+        _ => false,
+    };
+    if blocked {
+        return Ok(req
+            .into_response(HttpResponse::Gone().body(""))
+            .map_into_right_body());
+    }
+    Ok(next.call(req).await?.map_into_left_body())
+}
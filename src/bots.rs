@@ -0,0 +1,117 @@
+use crate::{storage::Storage, CounterLike};
+use actix_http::header::HttpDate;
+use actix_web::{http::Method, HttpRequest};
+use anyhow::Result;
+use prometheus::{IntCounterVec, Opts, Registry};
+use std::env;
+
+/// User-Agent fragments of crawlers and link previewers, matched without
+/// regard to case.
+const DEFAULT_USER_AGENTS: &[&str] = &[
+    "bot",
+    "crawler",
+    "spider",
+    "slurp",
+    "facebookexternalhit",
+    "embedly",
+    "preview",
+    "whatsapp",
+    "vkshare",
+    "headlesschrome",
+    "lighthouse",
+];
+
+/// Headers browsers send with speculative requests, which may never be seen.
+const PREFETCH_HEADERS: &[&str] = &["purpose", "sec-purpose", "x-purpose", "x-moz"];
+
+/// Decides which hits on the GET increment routes come from something other
+/// than a person viewing the page, so they can be answered without counting.
+pub struct BotFilter {
+    user_agents: Vec<String>,
+    prefetch: bool,
+    head: bool,
+    filtered: IntCounterVec,
+}
+
+impl BotFilter {
+    /// `BOT_USER_AGENTS` replaces the default User-Agent fragments with a
+    /// comma separated list, or turns matching them off when empty.
+    /// `BOT_FILTER_PREFETCH=false` and `BOT_FILTER_HEAD=false` count
+    /// prefetches and `HEAD` requests again.
+    pub fn from_env(registry: &Registry) -> Self {
+        let user_agents = match env::var("BOT_USER_AGENTS") {
+            Ok(list) => list
+                .split(',')
+                .map(|ua| ua.trim().to_lowercase())
+                .filter(|ua| !ua.is_empty())
+                .collect(),
+            Err(_) => DEFAULT_USER_AGENTS
+                .iter()
+                .map(|&ua| ua.to_owned())
+                .collect(),
+        };
+        let enabled = |name| env::var(name).map_or(true, |v| v != "false" && v != "0");
+        let filtered = IntCounterVec::new(
+            Opts::new(
+                "tickrs_filtered_hits_total",
+                "Hits answered without counting, by why they were filtered",
+            ),
+            &["reason"],
+        )
+        .unwrap();
+        registry.register(Box::new(filtered.clone())).unwrap();
+        Self {
+            user_agents,
+            prefetch: enabled("BOT_FILTER_PREFETCH"),
+            head: enabled("BOT_FILTER_HEAD"),
+            filtered,
+        }
+    }
+
+    fn reason(&self, req: &HttpRequest) -> Option<&'static str> {
+        if self.head && req.method() == Method::HEAD {
+            return Some("head");
+        }
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_lowercase)
+        };
+        if self.prefetch
+            && PREFETCH_HEADERS.iter().any(|&name| {
+                header(name).is_some_and(|v| v.contains("prefetch") || v.contains("preview"))
+            })
+        {
+            return Some("prefetch");
+        }
+        let user_agent = header("user-agent").unwrap_or_default();
+        if self.user_agents.iter().any(|ua| user_agent.contains(ua)) {
+            return Some("user_agent");
+        }
+        None
+    }
+
+    /// Whether the hit shouldn't be counted, tallying it if so.
+    pub fn filtered(&self, req: &HttpRequest) -> bool {
+        let Some(reason) = self.reason(req) else {
+            return false;
+        };
+        self.filtered.with_label_values(&[reason]).inc();
+        true
+    }
+}
+
+/// The value a filtered hit is shown, left as it is. One that doesn't exist
+/// yet is shown 0 rather than being made.
+pub async fn current<T: CounterLike>(id: &str, storage: &dyn Storage) -> Result<Option<i64>>
+where
+    HttpDate: for<'a> From<&'a T>,
+{
+    Ok(Some(
+        storage
+            .get(T::KIND, id)
+            .await?
+            .map_or(0, |record| record.value),
+    ))
+}
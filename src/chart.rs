@@ -0,0 +1,181 @@
+use crate::history::{Bucket, Step};
+use chrono::Utc;
+use serde::Deserialize;
+use std::fmt::Write;
+
+struct Theme {
+    background: &'static str,
+    foreground: &'static str,
+    stroke: &'static str,
+}
+
+// Matches the palette in templates/index.html.
+const LIGHT: Theme = Theme {
+    background: "#f1f3f5",
+    foreground: "#212529",
+    stroke: "#3b5bdb",
+};
+const DARK: Theme = Theme {
+    background: "#343a40",
+    foreground: "#f8f9fa",
+    stroke: "#748ffc",
+};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Kind {
+    Bar,
+    Line,
+}
+
+#[derive(Deserialize)]
+pub struct ChartQuery {
+    window: Option<String>,
+    width: Option<u32>,
+    height: Option<u32>,
+    theme: Option<String>,
+    #[serde(rename = "type")]
+    kind: Option<String>,
+}
+
+pub struct Chart {
+    step: Step,
+    from: i64,
+    buckets: i64,
+    width: u32,
+    height: u32,
+    theme: &'static Theme,
+    kind: Kind,
+}
+
+impl ChartQuery {
+    /// Resolves the query into chart settings, using `kind` if no `type` was
+    /// asked for. Returns `None` if any parameter is malformed.
+    pub fn chart(&self, kind: Kind) -> Option<Chart> {
+        let (step, buckets) = match self.window.as_deref().unwrap_or("24h") {
+            "1h" => (Step::Minute, 60),
+            "24h" | "1d" => (Step::Hour, 24),
+            "7d" => (Step::Hour, 7 * 24),
+            "30d" => (Step::Day, 30),
+            "90d" => (Step::Day, 90),
+            _ => return None,
+        };
+        let theme = match self.theme.as_deref().unwrap_or("light") {
+            "light" => &LIGHT,
+            "dark" => &DARK,
+            _ => return None,
+        };
+        let kind = match self.kind.as_deref() {
+            None => kind,
+            Some("bar") => Kind::Bar,
+            Some("line") => Kind::Line,
+            Some(_) => return None,
+        };
+        let now = Utc::now().timestamp();
+        let seconds = step.seconds();
+        Some(Chart {
+            step,
+            from: now - now % seconds - (buckets - 1) * seconds,
+            buckets,
+            width: self.width.unwrap_or(240).clamp(50, 1000),
+            height: self.height.unwrap_or(60).clamp(20, 400),
+            theme,
+            kind,
+        })
+    }
+}
+
+impl Chart {
+    pub fn step(&self) -> Step {
+        self.step
+    }
+
+    pub fn from(&self) -> i64 {
+        self.from
+    }
+
+    pub fn to(&self) -> i64 {
+        self.from + self.buckets * self.step.seconds() - 1
+    }
+
+    /// Spreads sparse history into one point per bucket. Bar charts show the
+    /// activity in each bucket, line charts carry the last value forward.
+    fn points(&self, history: &[Bucket]) -> Vec<i64> {
+        let seconds = self.step.seconds();
+        let mut last = history.first().map(|b| b.value - b.delta).unwrap_or(0);
+        let mut history = history.iter().peekable();
+        (0..self.buckets)
+            .map(|i| {
+                let bucket = self.from + i * seconds;
+                match history.next_if(|b| b.time.timestamp() == bucket) {
+                    Some(b) if self.kind == Kind::Bar => b.delta,
+                    Some(b) => {
+                        last = b.value;
+                        last
+                    }
+                    None if self.kind == Kind::Bar => 0,
+                    None => last,
+                }
+            })
+            .collect()
+    }
+
+    pub fn render(&self, history: &[Bucket]) -> String {
+        let points = self.points(history);
+        let min = points.iter().copied().min().unwrap_or(0).min(0) as f64;
+        let max = points.iter().copied().max().unwrap_or(0).max(1) as f64;
+        let (width, height) = (self.width as f64, self.height as f64);
+        let pad = 2.0;
+        let inner = height - pad * 2.0;
+        let y = |v: i64| pad + inner - (v as f64 - min) / (max - min) * inner;
+        let slot = width / points.len() as f64;
+
+        let mut body = String::new();
+        match self.kind {
+            Kind::Bar => {
+                let zero = y(0);
+                for (i, v) in points.iter().enumerate() {
+                    if *v == 0 {
+                        continue;
+                    }
+                    let top = y(*v).min(zero);
+                    let _ = write!(
+                        body,
+                        r#"<rect x="{:.1}" y="{:.1}" width="{:.1}" height="{:.1}"/>"#,
+                        i as f64 * slot + slot * 0.1,
+                        top,
+                        slot * 0.8,
+                        (y(*v) - zero).abs().max(1.0),
+                    );
+                }
+                body = format!(r#"<g fill="{}">{}</g>"#, self.theme.stroke, body);
+            }
+            Kind::Line => {
+                let path = points
+                    .iter()
+                    .enumerate()
+                    .map(|(i, v)| format!("{:.1},{:.1}", i as f64 * slot + slot / 2.0, y(*v)))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                let _ = write!(
+                    body,
+                    r#"<polyline fill="none" stroke="{}" stroke-width="1.5" stroke-linejoin="round" points="{}"/>"#,
+                    self.theme.stroke, path
+                );
+            }
+        }
+        format!(
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{w}" height="{h}" viewBox="0 0 {w} {h}" role="img">"#,
+                r#"<rect width="{w}" height="{h}" fill="{bg}"/>"#,
+                r#"<line x1="0" x2="{w}" y1="{zero:.1}" y2="{zero:.1}" stroke="{fg}" stroke-opacity=".2"/>"#,
+                r#"{body}</svg>"#,
+            ),
+            w = self.width,
+            h = self.height,
+            bg = self.theme.background,
+            fg = self.theme.foreground,
+            zero = y(0),
+            body = body,
+        )
+    }
+}
@@ -0,0 +1,72 @@
+use anyhow::Result;
+use image::{ImageFormat, Rgb, RgbImage};
+use std::io::Cursor;
+
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const SCALE: u32 = 3;
+const PADDING: u32 = 3;
+const CELL_WIDTH: u32 = GLYPH_WIDTH * SCALE + PADDING * 2;
+const CELL_HEIGHT: u32 = GLYPH_HEIGHT * SCALE + PADDING * 2;
+const BORDER: u32 = 1;
+
+const BACKGROUND: Rgb<u8> = Rgb([0x21, 0x25, 0x29]);
+const CELL: Rgb<u8> = Rgb([0x00, 0x00, 0x00]);
+const FOREGROUND: Rgb<u8> = Rgb([0x51, 0xcf, 0x66]);
+
+// Classic 5x7 dot-matrix glyphs, one row per byte with the low 5 bits used.
+const fn glyph(c: char) -> [u8; 7] {
+    match c {
+        '0' => [0x0e, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0e],
+        '1' => [0x04, 0x0c, 0x04, 0x04, 0x04, 0x04, 0x0e],
+        '2' => [0x0e, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1f],
+        '3' => [0x1f, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0e],
+        '4' => [0x02, 0x06, 0x0a, 0x12, 0x1f, 0x02, 0x02],
+        '5' => [0x1f, 0x10, 0x1e, 0x01, 0x01, 0x11, 0x0e],
+        '6' => [0x06, 0x08, 0x10, 0x1e, 0x11, 0x11, 0x0e],
+        '7' => [0x1f, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0e, 0x11, 0x11, 0x0e, 0x11, 0x11, 0x0e],
+        '9' => [0x0e, 0x11, 0x11, 0x0f, 0x01, 0x02, 0x0c],
+        '-' => [0x00, 0x00, 0x00, 0x1f, 0x00, 0x00, 0x00],
+        _ => [0x00; 7],
+    }
+}
+
+fn render(value: i64) -> RgbImage {
+    let text = value.to_string();
+    let cells = text.len() as u32;
+    let width = cells * (CELL_WIDTH + BORDER) + BORDER;
+    let height = CELL_HEIGHT + BORDER * 2;
+    let mut img = RgbImage::from_pixel(width, height, BACKGROUND);
+    for (i, c) in text.chars().enumerate() {
+        let cell_x = BORDER + i as u32 * (CELL_WIDTH + BORDER);
+        for y in 0..CELL_HEIGHT {
+            for x in 0..CELL_WIDTH {
+                img.put_pixel(cell_x + x, BORDER + y, CELL);
+            }
+        }
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                    continue;
+                }
+                let x = cell_x + PADDING + col * SCALE;
+                let y = BORDER + PADDING + row as u32 * SCALE;
+                for dy in 0..SCALE {
+                    for dx in 0..SCALE {
+                        img.put_pixel(x + dx, y + dy, FOREGROUND);
+                    }
+                }
+            }
+        }
+    }
+    img
+}
+
+/// Rasterises `value` as an old-school hit counter strip, encoded in
+/// `format` (PNG, GIF or JPEG).
+pub fn encode(value: i64, format: ImageFormat) -> Result<Vec<u8>> {
+    let mut out = Cursor::new(Vec::new());
+    render(value).write_to(&mut out, format)?;
+    Ok(out.into_inner())
+}
@@ -0,0 +1,171 @@
+use crate::storage::{Kind, Storage};
+use actix_web::{
+    rt::time::{timeout, Instant},
+    web::Bytes,
+    Error,
+};
+use futures_util::{stream, Stream};
+use std::{
+    env,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+
+/// How many changes can be queued for a slow subscriber before it starts
+/// missing some.
+const CAPACITY: usize = 1024;
+
+const DEFAULT_MAX_CONNECTIONS: usize = 1000;
+
+/// How often a comment is sent down an idle stream, so proxies keep it open
+/// and closed connections are noticed.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// A counter or gauge taking on a new value, or `None` once it's deleted.
+#[derive(Clone)]
+pub struct Change {
+    pub kind: Kind,
+    pub id: String,
+    pub value: Option<i64>,
+}
+
+/// What a subscription hears next.
+pub enum Received {
+    Change(Change),
+    /// Changes were missed, so any value might be out of date.
+    Lagged,
+}
+
+/// Fans out every change to whoever is listening. Open streams are capped by
+/// `EVENTS_MAX_CONNECTIONS`.
+pub struct Events {
+    sender: broadcast::Sender<Change>,
+    connections: Arc<AtomicUsize>,
+    max_connections: usize,
+}
+
+impl Events {
+    pub fn from_env() -> Self {
+        let max_connections = env::var("EVENTS_MAX_CONNECTIONS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_MAX_CONNECTIONS);
+        Self {
+            sender: broadcast::channel(CAPACITY).0,
+            connections: Arc::default(),
+            max_connections,
+        }
+    }
+
+    pub fn publish(&self, kind: Kind, id: &str, value: i64) {
+        self.send(kind, id, Some(value));
+    }
+
+    pub fn publish_deletion(&self, kind: Kind, id: &str) {
+        self.send(kind, id, None);
+    }
+
+    fn send(&self, kind: Kind, id: &str, value: Option<i64>) {
+        // Only fails if nobody is listening.
+        let _ = self.sender.send(Change {
+            kind,
+            id: id.to_owned(),
+            value,
+        });
+    }
+
+    /// Starts listening for changes, or returns `None` if too many
+    /// connections are open already.
+    pub fn subscribe(&self) -> Option<Subscription> {
+        let connections = self.connections.fetch_add(1, Ordering::Relaxed);
+        let subscription = Subscription {
+            receiver: self.sender.subscribe(),
+            connections: self.connections.clone(),
+        };
+        (connections < self.max_connections).then_some(subscription)
+    }
+}
+
+/// An open connection listening for changes. Frees its slot when dropped.
+pub struct Subscription {
+    receiver: broadcast::Receiver<Change>,
+    connections: Arc<AtomicUsize>,
+}
+
+impl Subscription {
+    /// Waits for the next change. Changes are shared by every ID, so a busy
+    /// one can push a quiet one's out of the queue, in which case it's up to
+    /// the caller to read afresh whatever it's following. Returns `None` if
+    /// the `Events` it came from is gone.
+    pub async fn recv(&mut self) -> Option<Received> {
+        match self.receiver.recv().await {
+            Ok(change) => Some(Received::Change(change)),
+            Err(RecvError::Lagged(_)) => Some(Received::Lagged),
+            Err(RecvError::Closed) => None,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// A `text/event-stream` body sending `value`, then every new value of the
+/// given counter or gauge, with a keep-alive comment whenever it's quiet. A
+/// `deleted` event ends the stream if it's deleted.
+pub fn stream(
+    subscription: Subscription,
+    storage: Arc<dyn Storage>,
+    kind: Kind,
+    id: String,
+    value: i64,
+) -> impl Stream<Item = Result<Bytes, Error>> {
+    let message = |value: Option<i64>| match value {
+        Some(value) => Ok(Bytes::from(format!("data: {}\n\n", value))),
+        None => Ok(Bytes::from_static(b"event: deleted\ndata:\n\n")),
+    };
+    stream::unfold(
+        (Some(subscription), Some(value), Instant::now() + KEEP_ALIVE),
+        move |(subscription, value, keep_alive)| {
+            let id = id.clone();
+            let storage = storage.clone();
+            async move {
+                // Gone once the deletion has been sent.
+                let mut subscription = subscription?;
+                if let Some(value) = value {
+                    return Some((message(Some(value)), (Some(subscription), None, keep_alive)));
+                }
+                loop {
+                    let wait = keep_alive.saturating_duration_since(Instant::now());
+                    let value = match timeout(wait, subscription.recv()).await {
+                        Ok(Some(Received::Change(change)))
+                            if change.kind == kind && change.id == id =>
+                        {
+                            change.value
+                        }
+                        Ok(Some(Received::Change(_))) => continue,
+                        Ok(Some(Received::Lagged)) => match storage.get(kind, &id).await {
+                            Ok(record) => record.map(|record| record.value),
+                            Err(_) => continue,
+                        },
+                        Ok(None) => return None,
+                        Err(_) => {
+                            let keep_alive = Instant::now() + KEEP_ALIVE;
+                            let comment = Ok(Bytes::from_static(b": keep-alive\n\n"));
+                            return Some((comment, (Some(subscription), None, keep_alive)));
+                        }
+                    };
+                    let keep_alive = Instant::now() + KEEP_ALIVE;
+                    let subscription = value.is_some().then_some(subscription);
+                    return Some((message(value), (subscription, None, keep_alive)));
+                }
+            }
+        },
+    )
+}
@@ -0,0 +1,156 @@
+use actix_web::{http::header, HttpResponse};
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    Minute,
+    Hour,
+    Day,
+}
+
+impl Step {
+    pub fn seconds(self) -> i64 {
+        match self {
+            Step::Minute => 60,
+            Step::Hour => 60 * 60,
+            Step::Day => 24 * 60 * 60,
+        }
+    }
+
+    /// How much history is shown when no `from` is given.
+    fn default_window(self) -> i64 {
+        match self {
+            Step::Minute => 60 * 60,
+            Step::Hour => 24 * 60 * 60,
+            Step::Day => 30 * 24 * 60 * 60,
+        }
+    }
+
+    fn parse(step: &str) -> Option<Self> {
+        match step {
+            "minute" | "1m" | "60" => Some(Step::Minute),
+            "hour" | "1h" | "3600" => Some(Step::Hour),
+            "day" | "1d" | "86400" => Some(Step::Day),
+            _ => None,
+        }
+    }
+}
+
+/// Whether a unix timestamp can be stored and shown as a date.
+fn representable(time: i64) -> bool {
+    DateTime::from_timestamp(time, 0).is_some()
+}
+
+fn parse_time(time: &str) -> Option<i64> {
+    time.parse::<i64>()
+        .ok()
+        .or_else(|| {
+            DateTime::parse_from_rfc3339(time)
+                .ok()
+                .map(|t| t.timestamp())
+        })
+        .filter(|&time| representable(time))
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    from: Option<String>,
+    to: Option<String>,
+    step: Option<String>,
+}
+
+impl HistoryQuery {
+    /// Resolves the query into a step and an inclusive `from..=to` range of
+    /// unix timestamps, or `None` if any parameter is malformed.
+    pub fn range(&self) -> Option<(Step, i64, i64)> {
+        let step = match &self.step {
+            Some(step) => Step::parse(step)?,
+            None => Step::Hour,
+        };
+        let to = match &self.to {
+            Some(to) => parse_time(to)?,
+            None => Utc::now().timestamp(),
+        };
+        let from = match &self.from {
+            Some(from) => parse_time(from)?,
+            None => to
+                .checked_sub(step.default_window())
+                .filter(|&from| representable(from))?,
+        };
+        (from <= to).then_some((step, from, to))
+    }
+}
+
+#[derive(Serialize)]
+pub struct Bucket {
+    pub time: DateTime<Utc>,
+    pub delta: i64,
+    pub value: i64,
+}
+
+impl Bucket {
+    pub fn new(bucket: i64, delta: i64, value: i64) -> Self {
+        Self {
+            time: DateTime::from_timestamp(bucket, 0).unwrap_or_default(),
+            delta,
+            value,
+        }
+    }
+}
+
+pub fn as_format(buckets: &[Bucket], ext: &str) -> HttpResponse {
+    match ext {
+        "json" => HttpResponse::Ok()
+            .insert_header(header::ContentType::json())
+            .json(buckets),
+        "csv" => {
+            let mut body = String::from("time,delta,value\n");
+            for bucket in buckets {
+                body.push_str(&format!(
+                    "{},{},{}\n",
+                    bucket.time.to_rfc3339_opts(SecondsFormat::Secs, true),
+                    bucket.delta,
+                    bucket.value
+                ));
+            }
+            HttpResponse::Ok()
+                .insert_header((header::CONTENT_TYPE, "text/csv; charset=utf-8"))
+                .body(body)
+        }
+        _ => HttpResponse::NotFound().body(""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn range(from: Option<&str>, to: Option<&str>) -> Option<(i64, i64)> {
+        let query = HistoryQuery {
+            from: from.map(str::to_owned),
+            to: to.map(str::to_owned),
+            step: Some("hour".to_owned()),
+        };
+        query.range().map(|(_, from, to)| (from, to))
+    }
+
+    #[test]
+    fn defaults_from_to_a_window_before_to() {
+        assert_eq!(range(None, Some("86400")), Some((0, 86400)));
+        assert_eq!(range(None, Some("1970-01-02T00:00:00Z")), Some((0, 86400)));
+    }
+
+    #[test]
+    fn rejects_times_that_arent_dates() {
+        assert_eq!(range(None, Some(&i64::MIN.to_string())), None);
+        assert_eq!(range(Some(&i64::MIN.to_string()), Some("0")), None);
+        assert_eq!(range(Some("0"), Some(&i64::MAX.to_string())), None);
+        assert_eq!(range(Some("yesterday"), None), None);
+    }
+
+    #[test]
+    fn rejects_backwards_ranges() {
+        assert_eq!(range(Some("10"), Some("5")), None);
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{ratelimit::mutates, storage::Storage};
+use actix_web::{
+    body::{self, BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    error::ErrorInternalServerError,
+    http::{header, Method, StatusCode},
+    middleware::Next,
+    rt,
+    web::{Bytes, Data},
+    Error, HttpResponse,
+};
+use anyhow::Result;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::env;
+
+const DEFAULT_WINDOW: i64 = 24 * 60 * 60;
+
+/// Longest key accepted in an `Idempotency-Key` header.
+const MAX_KEY_LEN: usize = 255;
+
+/// Responses with larger bodies aren't remembered.
+const MAX_BODY: u64 = 64 * 1024;
+
+/// Seconds a request has to answer before its key can be claimed again, in
+/// case it died without letting go.
+const LEASE: i64 = 30;
+
+/// How long keys are remembered for, from `IDEMPOTENCY_WINDOW_SECS`.
+pub struct Idempotency {
+    pub window: i64,
+}
+
+impl Idempotency {
+    pub fn from_env() -> Self {
+        let window = env::var("IDEMPOTENCY_WINDOW_SECS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_WINDOW);
+        Self { window }
+    }
+}
+
+/// What's remembered about an `Idempotency-Key`: the request it was first
+/// sent with, and the response once there is one.
+pub struct Remembered {
+    pub request: String,
+    pub response: Option<Response>,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub location: Option<String>,
+    pub content_type: Option<String>,
+    pub body: Vec<u8>,
+}
+
+impl Response {
+    fn replay(&self) -> HttpResponse {
+        let mut response =
+            HttpResponse::build(StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK));
+        if let Some(location) = &self.location {
+            response.insert_header((header::LOCATION, location.as_str()));
+        }
+        if let Some(content_type) = &self.content_type {
+            response.insert_header((header::CONTENT_TYPE, content_type.as_str()));
+        }
+        response
+            .insert_header(("Idempotent-Replayed", "true"))
+            .body(self.body.clone())
+    }
+}
+
+/// A claimed key that's forgotten when dropped, unless its response was
+/// stored. A request that fails or is cancelled part way is left free to be
+/// retried rather than stuck in progress.
+struct Claim {
+    key: Option<String>,
+    storage: Data<dyn Storage>,
+}
+
+impl Claim {
+    fn key(&self) -> &str {
+        self.key.as_deref().unwrap_or_default()
+    }
+
+    fn keep(mut self) {
+        self.key = None;
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let storage = self.storage.clone();
+            rt::spawn(async move {
+                let _ = storage.forget(&key).await;
+            });
+        }
+    }
+}
+
+/// Whether a request makes a new counter or gauge. Its write token isn't
+/// stored, so a repeat couldn't hand it back.
+fn creates(req: &ServiceRequest) -> bool {
+    req.method() == Method::POST && matches!(req.path(), "/c" | "/g")
+}
+
+/// Identifies a request by its method, URL and body, so a key can't be
+/// reused for a different change.
+fn fingerprint(req: &ServiceRequest, body: &[u8]) -> String {
+    let hash = Sha256::new()
+        .chain_update(req.method().as_str())
+        .chain_update([0])
+        .chain_update(req.uri().to_string())
+        .chain_update([0])
+        .chain_update(body)
+        .finalize();
+    hash.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Runs a request that changes something with an `Idempotency-Key` header
+/// only once within the window, answering repeats with the first response.
+pub async fn replay(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let key = req
+        .headers()
+        .get("Idempotency-Key")
+        .filter(|_| mutates(&req))
+        .map(|key| key.to_str().map(str::to_owned));
+    let (Some(key), Some(storage), Some(window)) = (
+        key,
+        req.app_data::<Data<dyn Storage>>().cloned(),
+        req.app_data::<Data<Idempotency>>().map(|i| i.window),
+    ) else {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    };
+    let respond = |req: ServiceRequest, response: HttpResponse| {
+        Ok(req.into_response(response).map_into_boxed_body())
+    };
+    let key = match key {
+        Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LEN => key,
+        _ => {
+            return respond(
+                req,
+                HttpResponse::BadRequest().body("invalid idempotency key"),
+            )
+        }
+    };
+    if creates(&req) {
+        return respond(
+            req,
+            HttpResponse::BadRequest().body("idempotency keys can't be used when creating"),
+        );
+    }
+
+    // The body is read up front to be hashed, then put back for the handler.
+    let body = req.extract::<Bytes>().await?;
+    let request = fingerprint(&req, &body);
+    req.set_payload(body.into());
+
+    let now = Utc::now().timestamp();
+    match storage
+        .remember(&key, &request, now - window, now - LEASE)
+        .await
+    {
+        Ok(None) => {}
+        Ok(Some(remembered)) if remembered.request != request => {
+            return respond(
+                req,
+                HttpResponse::UnprocessableEntity()
+                    .body("idempotency key was used for a different request"),
+            );
+        }
+        Ok(Some(Remembered {
+            response: Some(response),
+            ..
+        })) => return respond(req, response.replay()),
+        Ok(Some(_)) => {
+            return respond(
+                req,
+                HttpResponse::Conflict().body("a request with this idempotency key is in progress"),
+            );
+        }
+        Err(_) => return respond(req, HttpResponse::InternalServerError().body("")),
+    }
+
+    let claim = Claim {
+        key: Some(key),
+        storage,
+    };
+    let res = next.call(req).await?;
+    // Server errors are worth retrying, and streams can't be kept.
+    let remember = !res.status().is_server_error()
+        && match res.response().body().size() {
+            BodySize::None => true,
+            BodySize::Sized(n) => n <= MAX_BODY,
+            BodySize::Stream => false,
+        };
+    if !remember {
+        return Ok(res.map_into_boxed_body());
+    }
+
+    let (req, res) = res.into_parts();
+    let (res, body) = res.into_parts();
+    let body = body::to_bytes(body)
+        .await
+        .map_err(|e| ErrorInternalServerError(e.into() as Box<dyn std::error::Error>))?;
+    let header = |name| {
+        res.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_owned)
+    };
+    let response = Response {
+        status: res.status().as_u16(),
+        location: header(header::LOCATION),
+        content_type: header(header::CONTENT_TYPE),
+        body: body.to_vec(),
+    };
+    if claim.storage.respond(claim.key(), &response).await.is_ok() {
+        claim.keep();
+    }
+    Ok(ServiceResponse::new(
+        req,
+        res.set_body(body).map_into_boxed_body(),
+    ))
+}
@@ -0,0 +1,315 @@
+use crate::{
+    blocklist::Blocklist,
+    conditional_body,
+    storage::{Kind, Storage},
+    Counter, CounterLike, Gauge,
+};
+use actix_http::header::HttpDate;
+use actix_web::{
+    get, put,
+    web::{Data, Json, Path, Query},
+    HttpRequest, HttpResponse, Responder,
+};
+use anyhow::Result;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+
+/// Key/value pairs attached to a counter or gauge, e.g. `site:blog`.
+pub type Labels = BTreeMap<String, String>;
+
+/// Upper bound on labels attached to a single counter or gauge.
+const MAX_LABELS: usize = 16;
+const MAX_KEY_LEN: usize = 64;
+const MAX_VALUE_LEN: usize = 255;
+
+/// Records read at a time when adding up matches.
+const PAGE: i64 = 1000;
+
+/// Whether the labels fit in the database and can be written as a selector.
+pub fn valid(labels: &Labels) -> bool {
+    labels.len() <= MAX_LABELS
+        && labels.iter().all(|(key, value)| {
+            !key.is_empty()
+                && key.len() <= MAX_KEY_LEN
+                && !key.contains([':', ','])
+                && !value.is_empty()
+                && value.len() <= MAX_VALUE_LEN
+                && !value.contains(',')
+        })
+}
+
+/// Parses a selector like `site:blog,page:/about`, returning `None` if it's
+/// malformed.
+pub fn parse(selector: &str) -> Option<Labels> {
+    let mut labels = Labels::new();
+    for label in selector.split(',') {
+        let (key, value) = label.split_once(':')?;
+        labels.insert(key.to_owned(), value.to_owned());
+    }
+    valid(&labels).then_some(labels)
+}
+
+/// The labels to match for an optional `?label=` selector, or `None` if it's
+/// malformed. No selector matches everything.
+pub fn select(selector: Option<&str>) -> Option<Labels> {
+    match selector {
+        Some(selector) => parse(selector),
+        None => Some(Labels::new()),
+    }
+}
+
+/// Values of every counter and gauge having all of `labels`, leaving out
+/// blocked IDs as `/_metrics` does.
+async fn values(labels: &Labels, blocklist: &Blocklist, storage: &dyn Storage) -> Result<Vec<i64>> {
+    let mut values = Vec::new();
+    for kind in [Kind::Counter, Kind::Gauge] {
+        let mut after = String::new();
+        loop {
+            let records = storage.list(kind, "", labels, &after, PAGE).await?;
+            values.extend(
+                records
+                    .iter()
+                    .filter(|record| !blocklist.is_blocked(&record.id))
+                    .map(|record| record.value),
+            );
+            match records.last() {
+                Some(last) if records.len() as i64 == PAGE => after = last.id.clone(),
+                _ => break,
+            }
+        }
+    }
+    Ok(values)
+}
+
+#[derive(Deserialize)]
+struct LabelQuery {
+    label: Option<String>,
+}
+
+/// Sum of every counter and gauge matching `?label=`.
+#[get("/_sum")]
+async fn get_sum(
+    query: Query<LabelQuery>,
+    blocklist: Data<Blocklist>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    let Some(labels) = select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    let sum = values(&labels, &blocklist, storage.get_ref())
+        .await
+        .ok()
+        .and_then(|values| values.into_iter().try_fold(0i64, i64::checked_add));
+    if let Some(value) = sum {
+        HttpResponse::Ok().body(format!("{}", value))
+    } else {
+        HttpResponse::InternalServerError().body("")
+    }
+}
+
+/// Highest counter or gauge matching `?label=`.
+#[get("/_max")]
+async fn get_max(
+    query: Query<LabelQuery>,
+    blocklist: Data<Blocklist>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    let Some(labels) = select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    match values(&labels, &blocklist, storage.get_ref()).await {
+        Ok(values) => match values.into_iter().max() {
+            Some(value) => HttpResponse::Ok().body(format!("{}", value)),
+            None => HttpResponse::NotFound().body(""),
+        },
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[get("/c/{id}/labels")]
+async fn get_counter_labels(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    get_labels::<Counter>(&req, &path.0, storage.get_ref()).await
+}
+
+#[put("/c/{id}/labels")]
+async fn put_counter_labels(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    labels: Json<Labels>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    put_labels::<Counter>(&req, &path.0, &labels, storage.get_ref()).await
+}
+
+#[get("/g/{id}/labels")]
+async fn get_gauge_labels(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    get_labels::<Gauge>(&req, &path.0, storage.get_ref()).await
+}
+
+#[put("/g/{id}/labels")]
+async fn put_gauge_labels(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    labels: Json<Labels>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    put_labels::<Gauge>(&req, &path.0, &labels, storage.get_ref()).await
+}
+
+async fn get_labels<T: CounterLike>(
+    req: &HttpRequest,
+    id: &str,
+    storage: &dyn Storage,
+) -> HttpResponse
+where
+    HttpDate: for<'a> From<&'a T>,
+{
+    if !T::valid_id(id) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if T::get(id, storage).await.is_none() {
+        return T::missing(id, storage).await;
+    }
+    match storage.labels(T::KIND, id).await {
+        Ok(labels) => conditional_body(req, HttpResponse::Ok().json(labels)),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+/// Replaces the labels of a counter or gauge. Protected ones need their
+/// write token.
+async fn put_labels<T: CounterLike>(
+    req: &HttpRequest,
+    id: &str,
+    labels: &Labels,
+    storage: &dyn Storage,
+) -> HttpResponse
+where
+    HttpDate: for<'a> From<&'a T>,
+{
+    if !T::valid_id(id) || !valid(labels) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if T::get(id, storage).await.is_none() {
+        return T::missing(id, storage).await;
+    }
+    if !T::may_write(id, req, storage).await {
+        return HttpResponse::Forbidden().body("");
+    }
+    match storage.set_labels(T::KIND, id, labels).await {
+        Ok(()) => HttpResponse::Ok().json(labels),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_selectors() {
+        let labels = parse("site:blog,page:/about").unwrap();
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["site"], "blog");
+        assert_eq!(labels["page"], "/about");
+    }
+
+    #[test]
+    fn keeps_colons_in_values() {
+        assert_eq!(
+            parse("url:https://tick.rs").unwrap()["url"],
+            "https://tick.rs"
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_selectors() {
+        for selector in [
+            "",
+            "site",
+            "site:",
+            ":blog",
+            "site:blog,",
+            ",site:blog",
+            "site:blog,page",
+        ] {
+            assert!(parse(selector).is_none(), "{:?}", selector);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_labels() {
+        assert!(parse(&format!("{}:v", "k".repeat(MAX_KEY_LEN))).is_some());
+        assert!(parse(&format!("{}:v", "k".repeat(MAX_KEY_LEN + 1))).is_none());
+        assert!(parse(&format!("k:{}", "v".repeat(MAX_VALUE_LEN))).is_some());
+        assert!(parse(&format!("k:{}", "v".repeat(MAX_VALUE_LEN + 1))).is_none());
+
+        let selector = |n: usize| {
+            (0..n)
+                .map(|i| format!("k{}:v", i))
+                .collect::<Vec<_>>()
+                .join(",")
+        };
+        assert!(parse(&selector(MAX_LABELS)).is_some());
+        assert!(parse(&selector(MAX_LABELS + 1)).is_none());
+    }
+
+    #[test]
+    fn rejects_labels_that_cant_be_selected() {
+        let labels = |key: &str, value: &str| Labels::from([(key.to_owned(), value.to_owned())]);
+        assert!(valid(&labels("site", "blog")));
+        assert!(!valid(&labels("site", "")));
+        assert!(!valid(&labels("", "blog")));
+        assert!(!valid(&labels("si:te", "blog")));
+        assert!(!valid(&labels("si,te", "blog")));
+        assert!(!valid(&labels("site", "bl,og")));
+    }
+
+    #[test]
+    fn no_selector_matches_everything() {
+        assert_eq!(select(None), Some(Labels::new()));
+        assert_eq!(select(Some("")), None);
+    }
+
+    #[actix_web::test]
+    async fn leaves_blocked_ids_out_of_matches() {
+        let path = std::env::temp_dir().join(format!("tickrs-labels-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let storage = crate::storage::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        storage.migrate().await.unwrap();
+        let blog = parse("site:blog").unwrap();
+        for (kind, id, value) in [
+            (Kind::Counter, "post", 2),
+            (Kind::Counter, "spam-1", 50),
+            (Kind::Gauge, "spam-2", 70),
+            (Kind::Gauge, "readers", 3),
+        ] {
+            storage.create(kind, id, value).await.unwrap();
+            storage.set_labels(kind, id, &blog).await.unwrap();
+        }
+        storage.create(Kind::Counter, "other", 9).await.unwrap();
+
+        let blocklist = Blocklist::default();
+        storage.block("spam-", true).await.unwrap();
+        blocklist.refresh(storage.as_ref()).await.unwrap();
+
+        let mut matched = values(&blog, &blocklist, storage.as_ref()).await.unwrap();
+        matched.sort();
+        assert_eq!(matched, [2, 3]);
+        let mut all = values(&Labels::new(), &blocklist, storage.as_ref())
+            .await
+            .unwrap();
+        all.sort();
+        assert_eq!(all, [2, 3, 9]);
+    }
+}
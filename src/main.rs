@@ -1,20 +1,72 @@
 use actix_cors::Cors;
 use actix_http::header::HttpDate;
 use actix_web::{
-    get,
+    body::MessageBody,
+    delete, get,
     http::header,
-    middleware, post,
-    web::{Data, Path},
-    App, Error, HttpResponse, HttpServer, Responder,
+    middleware, post, put, route,
+    web::{Bytes, Data, Path, Query},
+    App, Error, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder,
 };
 use actix_web_prom::PrometheusMetricsBuilder;
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use askama::Template;
+use blocklist::Blocklist;
+use bots::BotFilter;
+use chart::ChartQuery;
 use chrono::{DateTime, Utc};
+use events::Events;
+use history::HistoryQuery;
+use idempotency::Idempotency;
+use image::ImageFormat;
 use nanoid::nanoid;
+use openmetrics::Format;
 use prometheus::{default_registry, IntGauge, Registry};
-use sqlx::{sqlite::SqlitePool, Pool, Sqlite};
-use std::{env, fmt::Display, net::Ipv4Addr, sync::LazyLock, time::Duration, time::SystemTime};
+use ratelimit::RateLimiter;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    env, fmt::Display, net::Ipv4Addr, sync::Arc, sync::LazyLock, time::Duration, time::SystemTime,
+};
+use storage::{
+    buffered::{self, Buffered},
+    notifying::Notifying,
+    Kind, Record, Storage,
+};
+use token::{AdminToken, Owner};
+use unique::Salts;
+
+mod admin;
+mod badge;
+mod batch;
+mod blocklist;
+mod bots;
+mod chart;
+mod digits;
+mod events;
+mod history;
+mod hll;
+mod idempotency;
+mod labels;
+mod openmetrics;
+mod ratelimit;
+mod statsd;
+mod storage;
+mod token;
+mod unique;
+mod ws;
+
+/// `Cache-Control` for reads of a counter or gauge. Caches have to check
+/// back each time unless `CACHE_MAX_AGE_SECS` lets them hold on for longer.
+static CACHE_CONTROL: LazyLock<String> = LazyLock::new(|| {
+    match env::var("CACHE_MAX_AGE_SECS")
+        .ok()
+        .and_then(|n| n.parse::<u32>().ok())
+    {
+        Some(age) if age > 0 => format!("public, max-age={}", age),
+        _ => "public, no-cache".to_owned(),
+    }
+});
 
 static REF: LazyLock<&'static str> = LazyLock::new(|| include_str!("../.git/HEAD"));
 static REF_MAIN: LazyLock<&'static str> = LazyLock::new(|| include_str!("../.git/refs/heads/main"));
@@ -75,45 +127,24 @@ impl DbMetrics {
         }
     }
 
-    async fn refresh(&self, pool: &Pool<Sqlite>, db_path: &str) {
-        // Row counts
-        if let Ok(n) = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM c")
-            .fetch_one(pool)
-            .await
-        {
-            self.counters_total.set(n);
+    async fn refresh(&self, storage: &dyn Storage) {
+        let Ok(stats) = storage.stats().await else {
+            return;
+        };
+        self.counters_total.set(stats.counters);
+        self.gauges_total.set(stats.gauges);
+        if let Some(n) = stats.size_bytes {
+            self.db_size_bytes.set(n);
         }
-        if let Ok(n) = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM g")
-            .fetch_one(pool)
-            .await
-        {
-            self.gauges_total.set(n);
+        if let Some(n) = stats.wal_size_bytes {
+            self.db_wal_size_bytes.set(n);
         }
-
-        // SQLite page stats
-        if let Ok(n) = sqlx::query_scalar::<_, i64>("PRAGMA page_count")
-            .fetch_one(pool)
-            .await
-        {
+        if let Some(n) = stats.page_count {
             self.db_page_count.set(n);
         }
-        if let Ok(n) = sqlx::query_scalar::<_, i64>("PRAGMA freelist_count")
-            .fetch_one(pool)
-            .await
-        {
+        if let Some(n) = stats.freelist_count {
             self.db_freelist_count.set(n);
         }
-
-        // File sizes from the filesystem
-        let path = db_path.strip_prefix("sqlite://").unwrap_or(db_path);
-        if let Ok(meta) = std::fs::metadata(path) {
-            self.db_size_bytes.set(meta.len() as i64);
-        }
-        let wal_path = format!("{}-wal", path);
-        match std::fs::metadata(&wal_path) {
-            Ok(meta) => self.db_wal_size_bytes.set(meta.len() as i64),
-            Err(_) => self.db_wal_size_bytes.set(0),
-        }
     }
 }
 
@@ -129,28 +160,79 @@ async fn main() -> Result<(), Error> {
         .unwrap();
 
     let db_url = env::var("DATABASE_URL").expect("DATABASE_URL not configured");
-    let pool = SqlitePool::connect(&db_url)
+    let storage = storage::connect(&db_url)
         .await
         .expect("Could not connect to database");
 
-    sqlx::query("PRAGMA journal_mode=WAL")
-        .execute(&pool)
+    storage.migrate().await.expect("Could not run migrations");
+    if env::args().any(|arg| arg == "--migrate-only") {
+        return Ok(());
+    }
+
+    let storage: Arc<dyn Storage> = match buffered::Config::from_env() {
+        Some(config) => {
+            let buffered: Arc<dyn Storage> = Arc::new(Buffered::new(storage, &config));
+            let buffered_bg = buffered.clone();
+            actix_web::rt::spawn(async move {
+                let mut ticker = actix_web::rt::time::interval(config.interval);
+                loop {
+                    ticker.tick().await;
+                    let _ = buffered_bg.flush().await;
+                }
+            });
+            buffered
+        }
+        None => storage,
+    };
+
+    let events = Arc::new(Events::from_env());
+    let storage: Arc<dyn Storage> = Arc::new(Notifying::new(storage, events.clone()));
+
+    let blocklist = Data::new(Blocklist::default());
+    blocklist
+        .refresh(storage.as_ref())
         .await
-        .expect("Could not enable WAL mode");
+        .expect("Could not load blocklist");
+
+    let limiter = Data::new(RateLimiter::from_env(&registry));
+    let bots = Data::new(BotFilter::from_env(&registry));
 
     // Register and start periodic DB metrics refresh
     let db_metrics = DbMetrics::register(&registry);
     let db_metrics_bg = db_metrics.clone();
-    let pool_bg = pool.clone();
-    let db_url_bg = db_url.clone();
+    let storage_bg = storage.clone();
+    let blocklist_bg = blocklist.clone();
+    let limiter_bg = limiter.clone();
     actix_web::rt::spawn(async move {
         let mut ticker = actix_web::rt::time::interval(Duration::from_secs(30));
         loop {
             ticker.tick().await;
-            db_metrics_bg.refresh(&pool_bg, &db_url_bg).await;
+            db_metrics_bg.refresh(storage_bg.as_ref()).await;
+            let _ = blocklist_bg.refresh(storage_bg.as_ref()).await;
+            limiter_bg.prune();
+        }
+    });
+
+    let idempotency = Data::new(Idempotency::from_env());
+    let storage_bg = storage.clone();
+    let window = idempotency.window;
+    actix_web::rt::spawn(async move {
+        let mut ticker = actix_web::rt::time::interval(Duration::from_secs(60 * 60));
+        loop {
+            ticker.tick().await;
+            let _ = storage_bg.prune_history().await;
+            let _ = storage_bg
+                .prune_idempotency_keys(Utc::now().timestamp() - window)
+                .await;
         }
     });
 
+    let admin = Data::new(AdminToken::from_env());
+    let salts = Data::new(Salts::default());
+    let storage_shutdown = storage.clone();
+    let storage: Data<dyn Storage> = Data::from(storage);
+    let events: Data<Events> = Data::from(events);
+
     let host: Ipv4Addr = env::var("HOST")
         .ok()
         .and_then(|h| h.parse().ok())
@@ -160,8 +242,26 @@ async fn main() -> Result<(), Error> {
         .and_then(|p| p.parse().ok())
         .unwrap_or(8126);
 
-    Ok(HttpServer::new(move || {
+    if let Some(port) = env::var("STATSD_PORT")
+        .ok()
+        .and_then(|p| p.parse::<u16>().ok())
+    {
+        let socket = actix_web::rt::net::UdpSocket::bind((host, port))
+            .await
+            .expect("Could not bind StatsD port");
+        actix_web::rt::spawn(statsd::listen(
+            socket,
+            storage.clone().into_inner(),
+            blocklist.clone(),
+            limiter.clone(),
+        ));
+    }
+
+    HttpServer::new(move || {
         App::new()
+            .wrap(middleware::from_fn(idempotency::replay))
+            .wrap(middleware::from_fn(blocklist::reject))
+            .wrap(middleware::from_fn(ratelimit::limit))
             .wrap(middleware::Compress::default())
             .wrap(middleware::NormalizePath::trim())
             .wrap(middleware::Logger::default())
@@ -169,38 +269,85 @@ async fn main() -> Result<(), Error> {
             .wrap(
                 Cors::default()
                     .allow_any_origin()
-                    .allowed_methods(vec!["GET", "POST"])
+                    .allowed_methods(vec!["GET", "POST", "PUT", "DELETE"])
                     .allowed_header(header::CONTENT_TYPE)
+                    .allowed_header(header::AUTHORIZATION)
+                    .allowed_header("idempotency-key")
+                    .expose_headers(vec!["x-write-token", "idempotent-replayed", "retry-after"])
                     .max_age(3600),
             )
-            .app_data(Data::new(pool.clone()))
+            .app_data(storage.clone())
+            .app_data(admin.clone())
+            .app_data(blocklist.clone())
+            .app_data(events.clone())
+            .app_data(salts.clone())
+            .app_data(idempotency.clone())
+            .app_data(limiter.clone())
+            .app_data(bots.clone())
             .service(index)
             .service(favicon)
             .service(health)
+            .service(admin::scope())
+            .service(batch::post_batch)
+            .service(ws::get_ws)
+            .service(openmetrics::get_metrics)
             .service(get_total)
             .service(get_highest)
+            .service(labels::get_sum)
+            .service(labels::get_max)
             .service(new_counter)
             .service(get_counter_ext)
             .service(get_counter_metrics)
+            .service(get_counter_history_ext)
+            .service(get_counter_chart)
+            .service(get_counter_events)
+            .service(get_counter_history)
+            .service(labels::get_counter_labels)
+            .service(labels::put_counter_labels)
             .service(get_plus_counter_ext)
             .service(get_plus_counter)
             .service(get_counter)
             .service(post_counter)
+            .service(protect_counter)
+            .service(unprotect_counter)
+            .service(reset_counter)
+            .service(delete_counter)
             .service(new_gauge)
             .service(get_gauge_ext)
             .service(get_gauge_metrics)
+            .service(get_gauge_history_ext)
+            .service(get_gauge_chart)
+            .service(get_gauge_events)
+            .service(get_gauge_history)
+            .service(labels::get_gauge_labels)
+            .service(labels::put_gauge_labels)
             .service(get_minus_gauge_ext)
             .service(get_plus_gauge_ext)
             .service(get_minus_gauge)
             .service(get_plus_gauge)
             .service(get_gauge)
             .service(post_gauge)
+            .service(protect_gauge)
+            .service(unprotect_gauge)
+            .service(reset_gauge)
+            .service(delete_gauge)
             .service(post_minus_gauge)
+            .service(put_gauge_ext)
+            .service(put_gauge)
+            .service(set_gauge_ext)
+            .service(set_gauge)
     })
     .shutdown_timeout(30)
     .bind((host, port))?
     .run()
-    .await?)
+    .await?;
+
+    // Write out anything still held by the write buffer
+    storage_shutdown
+        .flush()
+        .await
+        .expect("Could not flush write buffer");
+    Ok(())
 }
 
 #[derive(Template)]
@@ -230,22 +377,198 @@ async fn health() -> impl Responder {
     HttpResponse::Ok().body(HASH.as_bytes())
 }
 
+#[derive(Deserialize)]
+struct CreateQuery {
+    protected: Option<bool>,
+    label: Option<String>,
+    unique: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct DeltaQuery {
+    by: Option<i64>,
+}
+
+impl DeltaQuery {
+    /// How much to change by: `?by=`, then `{"by": n}` in the body, then 1.
+    /// Returns `None` if there's a body that isn't a valid one.
+    fn by(&self, body: &[u8]) -> Option<i64> {
+        let body = if body.is_empty() {
+            None
+        } else {
+            serde_json::from_slice::<DeltaQuery>(body).ok()?.by
+        };
+        Some(self.by.or(body).unwrap_or(1))
+    }
+}
+
+#[derive(Deserialize)]
+struct SetQuery {
+    value: Option<i64>,
+}
+
+/// Marks the response to a GET that changed something as uncacheable, so a
+/// cache in front can't swallow later hits.
+fn uncached(mut response: HttpResponse) -> HttpResponse {
+    response
+        .headers_mut()
+        .insert(header::CACHE_CONTROL, "no-store".parse().unwrap());
+    response
+}
+
+/// Tags a successful read with an `ETag` and `Cache-Control`, or swaps it
+/// for `304 Not Modified` if the client's copy is still current. `state` has
+/// hashed whatever the response was made from. Tags are strong, so the
+/// response is kept from `Compress`, which would send the same one with every
+/// encoding.
+fn conditional(req: &HttpRequest, mut response: HttpResponse, state: Sha256) -> HttpResponse {
+    if !response.status().is_success() {
+        return response;
+    }
+    // Representations differ by URL and, for metrics, by content type.
+    let content_type = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .map(|value| value.as_bytes())
+        .unwrap_or_default();
+    let hash = state
+        .chain_update([0])
+        .chain_update(req.uri().to_string())
+        .chain_update([0])
+        .chain_update(content_type)
+        .finalize();
+    let tag = hash[..16].iter().map(|b| format!("{:02x}", b)).collect();
+    let etag = header::EntityTag::new_strong(tag);
+    let headers = response.headers_mut();
+    headers.insert(header::ETAG, etag.to_string().parse().unwrap());
+    headers.insert(header::CACHE_CONTROL, CACHE_CONTROL.parse().unwrap());
+    headers.insert(header::CONTENT_ENCODING, "identity".parse().unwrap());
+
+    let fresh = match req.get_header::<header::IfNoneMatch>() {
+        Some(header::IfNoneMatch::Any) => true,
+        Some(header::IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        // Dates only count when there's no ETag to compare.
+        None => {
+            let modified = headers
+                .get(header::LAST_MODIFIED)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<HttpDate>().ok());
+            match (req.get_header::<header::IfModifiedSince>(), modified) {
+                (Some(since), Some(modified)) => modified <= since.0,
+                _ => false,
+            }
+        }
+    };
+    if !fresh {
+        return response;
+    }
+    let mut not_modified = HttpResponse::NotModified();
+    for name in [
+        header::ETAG,
+        header::CACHE_CONTROL,
+        header::LAST_MODIFIED,
+        header::VARY,
+    ] {
+        if let Some(value) = headers.get(&name) {
+            not_modified.insert_header((name, value.clone()));
+        }
+    }
+    not_modified.finish()
+}
+
+/// Like `conditional`, for reads such as history that depend on more than
+/// the current value, so are tagged by their body.
+fn conditional_body(req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+    let (response, body) = response.into_parts();
+    match body.try_into_bytes() {
+        Ok(body) => {
+            let state = Sha256::new().chain_update(&body);
+            conditional(req, response.set_body(body).map_into_boxed_body(), state)
+        }
+        Err(body) => response.set_body(body),
+    }
+}
+
+#[derive(Deserialize, Default)]
+struct FormatQuery {
+    label: Option<String>,
+    style: Option<String>,
+    color: Option<String>,
+}
+
 trait CounterLike: Sized + Display
 where
     HttpDate: for<'a> std::convert::From<&'a Self>,
 {
+    const KIND: Kind;
+
     #[inline(always)]
     fn valid_id(id: &str) -> bool {
         !id.is_empty() && id.len() < 255 && id.is_ascii()
     }
 
     #[inline(always)]
-    async fn create(pool: &Pool<Sqlite>) -> Result<Self> {
-        Self::create_with_id_and_value(&nanoid!(12, &nanoid::alphabet::SAFE), pool, 0).await
+    async fn create(storage: &dyn Storage) -> Result<Self> {
+        Self::create_with_id_and_value(&nanoid!(12, &nanoid::alphabet::SAFE), storage, 0).await
+    }
+
+    async fn create_with_id_and_value(id: &str, storage: &dyn Storage, value: i64) -> Result<Self> {
+        storage.create(Self::KIND, id, value).await?;
+        Ok(Self::new(id, value))
+    }
+
+    async fn get(id: &str, storage: &dyn Storage) -> Option<Self> {
+        match storage.get(Self::KIND, id).await {
+            Ok(Some(record)) => Some(Self::from_record(record)),
+            _ => None,
+        }
+    }
+
+    async fn deleted(id: &str, storage: &dyn Storage) -> bool {
+        storage.deleted(Self::KIND, id).await.unwrap_or(false)
+    }
+
+    async fn delete(id: &str, storage: &dyn Storage) -> Result<bool> {
+        storage.delete(Self::KIND, id).await
+    }
+
+    async fn reset(id: &str, storage: &dyn Storage) -> Result<bool> {
+        storage.reset(Self::KIND, id).await
+    }
+
+    async fn owner(id: &str, storage: &dyn Storage) -> Result<Option<Owner>> {
+        storage.owner(Self::KIND, id).await
+    }
+
+    async fn claim(
+        id: &str,
+        token_hash: &str,
+        protected: bool,
+        storage: &dyn Storage,
+    ) -> Result<()> {
+        storage.claim(Self::KIND, id, token_hash, protected).await
+    }
+
+    async fn set_protected(id: &str, protected: bool, storage: &dyn Storage) -> Result<()> {
+        storage.set_protected(Self::KIND, id, protected).await
     }
 
-    fn as_format(&self, ext: &str) -> HttpResponse {
+    fn as_format(&self, ext: &str, query: &FormatQuery) -> HttpResponse {
         match ext {
+            "png" | "jpg" | "gif" if query.style.as_deref() == Some("digits") => {
+                let (format, content_type) = match ext {
+                    "png" => (ImageFormat::Png, "image/png"),
+                    "jpg" => (ImageFormat::Jpeg, "image/jpeg"),
+                    _ => (ImageFormat::Gif, "image/gif"),
+                };
+                match digits::encode(self.value(), format) {
+                    Ok(body) => HttpResponse::Ok()
+                        .insert_header(header::LastModified(self.into()))
+                        .insert_header((header::CONTENT_TYPE, content_type))
+                        .body(body),
+                    Err(e) => HttpResponse::InternalServerError().body(e.to_string()),
+                }
+            }
             "png" => HttpResponse::Ok()
                 .insert_header(header::LastModified(self.into()))
                 .insert_header(header::ContentType::png())
@@ -261,7 +584,20 @@ where
             "svg" => HttpResponse::Ok()
                 .insert_header(header::LastModified(self.into()))
                 .insert_header((header::CONTENT_TYPE, "image/svg+xml; charset=utf-8"))
-                .body("<svg xmlns=\"http://www.w3.org/2000/svg\"/>"),
+                .body(badge::render(
+                    query.label.as_deref(),
+                    &self.to_string(),
+                    query.color.as_deref(),
+                )),
+            "shields" => HttpResponse::Ok()
+                .insert_header(header::LastModified(self.into()))
+                .insert_header(header::ContentType::json())
+                .json(serde_json::json!({
+                    "schemaVersion": 1,
+                    "label": query.label.as_deref().unwrap_or("count"),
+                    "message": self.to_string(),
+                    "color": query.color.as_deref().unwrap_or("blue"),
+                })),
             "json" => HttpResponse::Ok()
                 .insert_header(header::LastModified(self.into()))
                 .insert_header(header::ContentType::json())
@@ -274,10 +610,85 @@ where
         }
     }
 
-    async fn create_with_id_and_value(id: &str, pool: &Pool<Sqlite>, value: i64) -> Result<Self>;
-    async fn get(id: &str, pool: &Pool<Sqlite>) -> Option<Self>;
-    fn as_openmetrics(&self) -> HttpResponse;
+    /// Whether `req` may change the counter. Anyone can, unless the owner
+    /// has protected it, in which case the owner's write token is required.
+    async fn may_write(id: &str, req: &HttpRequest, storage: &dyn Storage) -> bool {
+        match Self::owner(id, storage).await {
+            Ok(Some(owner)) if owner.protected => token::matches(req, &owner.token_hash),
+            Ok(_) => true,
+            Err(_) => false,
+        }
+    }
+
+    /// The response refusing a change from `req`, if it's refused: `410 Gone`
+    /// if the counter was deleted, as writing would bring it back without its
+    /// owner, or `403 Forbidden` if it's protected and `req` lacks the token.
+    async fn refuse_write(
+        id: &str,
+        req: &HttpRequest,
+        storage: &dyn Storage,
+    ) -> Option<HttpResponse> {
+        if Self::deleted(id, storage).await {
+            Some(HttpResponse::Gone().body(""))
+        } else if !Self::may_write(id, req, storage).await {
+            Some(HttpResponse::Forbidden().body(""))
+        } else {
+            None
+        }
+    }
+
+    async fn is_owner(id: &str, req: &HttpRequest, storage: &dyn Storage) -> bool {
+        matches!(
+            Self::owner(id, storage).await,
+            Ok(Some(owner)) if token::matches(req, &owner.token_hash)
+        )
+    }
+
+    /// Whether `req` may delete or reset the counter, which needs the owner's
+    /// write token or the admin token.
+    async fn may_manage(
+        id: &str,
+        req: &HttpRequest,
+        admin: &AdminToken,
+        storage: &dyn Storage,
+    ) -> bool {
+        admin.matches(req) || Self::is_owner(id, req, storage).await
+    }
+
+    /// The response for an ID that `get` couldn't find: `410 Gone` if it was
+    /// deleted, `404 Not Found` if it never existed.
+    async fn missing(id: &str, storage: &dyn Storage) -> HttpResponse {
+        if Self::deleted(id, storage).await {
+            HttpResponse::Gone().body("")
+        } else {
+            HttpResponse::NotFound().body("")
+        }
+    }
+
+    fn as_openmetrics(&self, format: Format) -> HttpResponse {
+        let mut body = String::new();
+        openmetrics::family(&mut body, format, Self::KIND, [(self.id(), self.value())]);
+        openmetrics::finish(&mut body, format);
+        HttpResponse::Ok()
+            .insert_header(header::LastModified(self.into()))
+            .insert_header((header::CONTENT_TYPE, format.content_type()))
+            .insert_header((header::VARY, "Accept"))
+            .body(body)
+    }
+
+    /// Tags a read of the value with an `ETag` and `Cache-Control`, or swaps
+    /// it for `304 Not Modified` if the client's copy is still current.
+    fn conditional(&self, req: &HttpRequest, response: HttpResponse) -> HttpResponse {
+        let state = Sha256::new()
+            .chain_update(self.id())
+            .chain_update([0])
+            .chain_update(self.value().to_be_bytes())
+            .chain_update(HttpDate::from(self).to_string());
+        conditional(req, response, state)
+    }
+
     fn new(id: &str, value: i64) -> Self;
+    fn from_record(record: Record) -> Self;
     fn id(&self) -> &str;
     fn value(&self) -> i64;
 }
@@ -290,6 +701,8 @@ pub struct Counter {
 }
 
 impl CounterLike for Counter {
+    const KIND: Kind = Kind::Counter;
+
     #[inline(always)]
     fn new(id: &str, value: i64) -> Self {
         Self {
@@ -299,6 +712,14 @@ impl CounterLike for Counter {
         }
     }
 
+    fn from_record(record: Record) -> Self {
+        Self {
+            id: record.id,
+            value: record.value,
+            updated_at: record.updated_at,
+        }
+    }
+
     #[inline(always)]
     fn id(&self) -> &str {
         &self.id
@@ -308,72 +729,29 @@ impl CounterLike for Counter {
     fn value(&self) -> i64 {
         self.value
     }
-
-    async fn create_with_id_and_value(id: &str, pool: &Pool<Sqlite>, value: i64) -> Result<Self> {
-        let mut conn = pool.acquire().await?;
-        sqlx::query!(
-            r#"INSERT INTO c ( nano_id, value ) VALUES ( ?1, ?2 )"#,
-            id,
-            value
-        )
-        .execute(&mut *conn)
-        .await?;
-
-        Ok(Self {
-            id: id.to_owned(),
-            value,
-            updated_at: SystemTime::now().into(),
-        })
-    }
-
-    async fn get(id: &str, pool: &Pool<Sqlite>) -> Option<Self> {
-        if let Ok(mut conn) = pool.acquire().await {
-            sqlx::query!(r#"SELECT value, updated_at FROM c WHERE nano_id = ?1"#, id)
-                .fetch_one(&mut *conn)
-                .await
-                .map(|res| {
-                    Some(Self {
-                        id: id.to_owned(),
-                        value: res.value,
-                        updated_at: res.updated_at.and_utc(),
-                    })
-                })
-                .unwrap_or(None)
-        } else {
-            None
-        }
-    }
-
-    fn as_openmetrics(&self) -> HttpResponse {
-        HttpResponse::Ok()
-            .insert_header(header::LastModified(self.into()))
-            .insert_header((
-                header::CONTENT_TYPE,
-                "text/plain; version=0.0.4; charset=utf-8",
-            ))
-            .body(format!(
-                "# TYPE {} counter\n{}_count {}",
-                self.id(),
-                self.id(),
-                self
-            ))
-    }
 }
 
 impl Counter {
-    async fn increment_or_create(id: &str, pool: &Pool<Sqlite>) -> Result<i64> {
-        let mut conn = pool.acquire().await?;
-        let rec = sqlx::query!(
-            r#"INSERT INTO c (nano_id, value) VALUES (?1, 1)
-               ON CONFLICT(nano_id) DO UPDATE SET
-                 value = value + 1,
-                 updated_at = datetime('now', 'utc')
-               RETURNING value"#,
-            id
-        )
-        .fetch_one(&mut *conn)
-        .await?;
-        Ok(rec.value)
+    /// Adds `by` to the counter, creating it if needed. Returns `None` if the
+    /// counter would overflow.
+    async fn increment_or_create(id: &str, by: i64, storage: &dyn Storage) -> Result<Option<i64>> {
+        ensure!(by >= 0, "counters cannot be decremented");
+        storage.increment(Kind::Counter, id, by).await
+    }
+
+    /// Counts a hit from `req`: one more for most counters, the visitor for
+    /// unique ones. Returns `None` if the counter would overflow.
+    async fn hit(
+        id: &str,
+        req: &HttpRequest,
+        salts: &Salts,
+        storage: &dyn Storage,
+    ) -> Result<Option<i64>> {
+        if !storage.unique(id).await? {
+            return Self::increment_or_create(id, 1, storage).await;
+        }
+        let salt = salts.today(storage).await?;
+        storage.visit(id, unique::visitor(req, &salt)).await
     }
 }
 
@@ -398,6 +776,8 @@ pub struct Gauge {
 }
 
 impl CounterLike for Gauge {
+    const KIND: Kind = Kind::Gauge;
+
     #[inline(always)]
     fn new(id: &str, value: i64) -> Self {
         Self {
@@ -407,6 +787,14 @@ impl CounterLike for Gauge {
         }
     }
 
+    fn from_record(record: Record) -> Self {
+        Self {
+            id: record.id,
+            value: record.value,
+            updated_at: record.updated_at,
+        }
+    }
+
     #[inline(always)]
     fn id(&self) -> &str {
         &self.id
@@ -416,87 +804,27 @@ impl CounterLike for Gauge {
     fn value(&self) -> i64 {
         self.value
     }
+}
 
-    async fn create_with_id_and_value(id: &str, pool: &Pool<Sqlite>, value: i64) -> Result<Self> {
-        let mut conn = pool.acquire().await?;
-        sqlx::query!(
-            r#"INSERT INTO g ( nano_id, value ) VALUES ( ?1, ?2 )"#,
-            id,
-            value
-        )
-        .execute(&mut *conn)
-        .await?;
-
-        Ok(Self {
-            id: id.to_owned(),
-            value,
-            updated_at: SystemTime::now().into(),
-        })
-    }
-
-    async fn get(id: &str, pool: &Pool<Sqlite>) -> Option<Self> {
-        if let Ok(mut conn) = pool.acquire().await {
-            sqlx::query!(r#"SELECT value, updated_at FROM g WHERE nano_id = ?1"#, id)
-                .fetch_one(&mut *conn)
-                .await
-                .map(|res| {
-                    Some(Self {
-                        id: id.to_owned(),
-                        value: res.value,
-                        updated_at: res.updated_at.and_utc(),
-                    })
-                })
-                .unwrap_or(None)
-        } else {
-            None
+impl Gauge {
+    /// Subtracts `by` from the gauge, creating it if needed. Returns `None` if
+    /// the gauge would overflow.
+    async fn decrement_or_create(id: &str, by: i64, storage: &dyn Storage) -> Result<Option<i64>> {
+        match by.checked_neg() {
+            Some(by) => Self::increment_or_create(id, by, storage).await,
+            None => Ok(None),
         }
     }
 
-    fn as_openmetrics(&self) -> HttpResponse {
-        HttpResponse::Ok()
-            .insert_header(header::LastModified(self.into()))
-            .insert_header((
-                header::CONTENT_TYPE,
-                "text/plain; version=0.0.4; charset=utf-8",
-            ))
-            .body(format!(
-                "# TYPE {} gauge\n{}_count {}",
-                self.id(),
-                self.id(),
-                self
-            ))
+    /// Adds `by` (which may be negative) to the gauge, creating it if needed.
+    /// Returns `None` if the gauge would overflow.
+    async fn increment_or_create(id: &str, by: i64, storage: &dyn Storage) -> Result<Option<i64>> {
+        storage.increment(Kind::Gauge, id, by).await
     }
-}
 
-impl Gauge {
-    async fn decrement_or_create(id: &str, pool: &Pool<Sqlite>) -> Result<i64> {
-        let mut conn = pool.acquire().await?;
-        let rec = sqlx::query!(
-            r#"INSERT INTO g (nano_id, value) VALUES (?1, -1)
-               ON CONFLICT(nano_id) DO UPDATE SET
-                 value = value - 1,
-                 updated_at = datetime('now', 'utc')
-               RETURNING value"#,
-            id
-        )
-        .fetch_one(&mut *conn)
-        .await?;
-        Ok(rec.value)
-    }
-
-    async fn increment_or_create(id: &str, pool: &Pool<Sqlite>) -> Result<i64> {
-        let mut conn = pool.acquire().await?;
-        let rec = sqlx::query!(
-            r#"INSERT INTO g (nano_id, value) VALUES (?1, 1)
-               ON CONFLICT(nano_id) DO UPDATE SET
-                 value = value + 1,
-                 updated_at = datetime('now', 'utc')
-               RETURNING value"#,
-            id
-        )
-        .fetch_one(&mut *conn)
-        .await?;
-        Ok(rec.value)
+    /// Sets the gauge to `value`, creating it if needed.
+    async fn set_or_create(id: &str, value: i64, storage: &dyn Storage) -> Result<i64> {
+        storage.set(Kind::Gauge, id, value).await
     }
 }
 
@@ -514,13 +842,8 @@ impl Display for Gauge {
 }
 
 #[get("/_total")]
-async fn get_total(pool: Data<Pool<Sqlite>>) -> impl Responder {
-    let value =
-        sqlx::query!(r#"SELECT (SELECT count(id) FROM c) + (SELECT count(id) FROM g) as value"#)
-            .fetch_one(pool.get_ref())
-            .await
-            .map(|res| res.value);
-    if let Ok(value) = value {
+async fn get_total(storage: Data<dyn Storage>) -> impl Responder {
+    if let Ok(value) = storage.total().await {
         HttpResponse::Ok().body(format!("{}", value))
     } else {
         HttpResponse::InternalServerError().body("")
@@ -528,14 +851,8 @@ async fn get_total(pool: Data<Pool<Sqlite>>) -> impl Responder {
 }
 
 #[get("/_highest")]
-async fn get_highest(pool: Data<Pool<Sqlite>>) -> impl Responder {
-    let value = sqlx::query!(
-        r#"SELECT value FROM c UNION SELECT value from g ORDER BY value DESC LIMIT 1"#
-    )
-    .fetch_one(pool.get_ref())
-    .await
-    .map(|res| res.value);
-    if let Ok(value) = value {
+async fn get_highest(storage: Data<dyn Storage>) -> impl Responder {
+    if let Ok(value) = storage.highest().await {
         HttpResponse::Ok().body(format!("{}", value))
     } else {
         HttpResponse::InternalServerError().body("")
@@ -543,36 +860,77 @@ async fn get_highest(pool: Data<Pool<Sqlite>>) -> impl Responder {
 }
 
 #[post("/c")]
-async fn new_counter(pool: Data<Pool<Sqlite>>) -> impl Responder {
-    if let Ok(counter) = Counter::create(pool.get_ref()).await {
-        HttpResponse::SeeOther()
-            .insert_header((header::LOCATION, format!("/c/{}", counter.id)))
-            .insert_header(header::ContentType::plaintext())
-            .body(counter.id)
-    } else {
-        HttpResponse::InternalServerError().body("")
+async fn new_counter(query: Query<CreateQuery>, storage: Data<dyn Storage>) -> impl Responder {
+    let Some(labels) = labels::select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    let Ok(counter) = Counter::create(storage.get_ref()).await else {
+        return HttpResponse::InternalServerError().body("");
+    };
+    if query.unique.unwrap_or(false) && storage.make_unique(&counter.id).await.is_err() {
+        return HttpResponse::InternalServerError().body("");
     }
+    let (token, token_hash) = token::generate();
+    let protected = query.protected.unwrap_or(false);
+    if Counter::claim(&counter.id, &token_hash, protected, storage.get_ref())
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().body("");
+    }
+    if !labels.is_empty()
+        && storage
+            .set_labels(Kind::Counter, &counter.id, &labels)
+            .await
+            .is_err()
+    {
+        return HttpResponse::InternalServerError().body("");
+    }
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, format!("/c/{}", counter.id)))
+        .insert_header(("X-Write-Token", token))
+        .insert_header(header::ContentType::plaintext())
+        .body(counter.id)
 }
 
 #[get("/c/{id}")]
-async fn get_counter(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn get_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Counter::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Some(counter) = Counter::get(&path.0, pool.get_ref()).await {
-        counter.as_format("txt")
+    if let Some(counter) = Counter::get(&path.0, storage.get_ref()).await {
+        counter.conditional(&req, counter.as_format("txt", &FormatQuery::default()))
     } else {
-        HttpResponse::NotFound().body("")
+        Counter::missing(&path.0, storage.get_ref()).await
     }
 }
 
-#[get("/c+/{id}")]
-async fn get_plus_counter(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+#[route("/c+/{id}", method = "GET", method = "HEAD")]
+async fn get_plus_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    salts: Data<Salts>,
+    bots: Data<BotFilter>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Counter::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Counter::increment_or_create(&path.0, pool.get_ref()).await {
+    if let Some(refusal) = Counter::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let hit = if bots.filtered(&req) {
+        bots::current::<Counter>(&path.0, storage.get_ref()).await
+    } else {
+        Counter::hit(&path.0, &req, &salts, storage.get_ref()).await
+    };
+    if let Ok(Some(i)) = hit {
         HttpResponse::SeeOther()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
             .insert_header((header::LOCATION, format!("/c/{}", path.0)))
             .insert_header(header::ContentType::plaintext())
             .body(format!("{}", i))
@@ -581,91 +939,263 @@ async fn get_plus_counter(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> im
     }
 }
 
-#[get("/c+/{id}.{ext}")]
+#[route("/c+/{id}.{ext}", method = "GET", method = "HEAD")]
 async fn get_plus_counter_ext(
+    req: HttpRequest,
     path: Path<(String, String)>,
-    pool: Data<Pool<Sqlite>>,
+    query: Query<FormatQuery>,
+    salts: Data<Salts>,
+    bots: Data<BotFilter>,
+    storage: Data<dyn Storage>,
 ) -> impl Responder {
     if !Counter::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Counter::increment_or_create(&path.0, pool.get_ref()).await {
-        Counter::new(&path.0, i).as_format(&path.1)
+    if let Some(refusal) = Counter::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let hit = if bots.filtered(&req) {
+        bots::current::<Counter>(&path.0, storage.get_ref()).await
+    } else {
+        Counter::hit(&path.0, &req, &salts, storage.get_ref()).await
+    };
+    if let Ok(Some(i)) = hit {
+        uncached(Counter::new(&path.0, i).as_format(&path.1, &query))
     } else {
         HttpResponse::NotFound().body("")
     }
 }
 
 #[get("/c/{id}.{ext}")]
-async fn get_counter_ext(path: Path<(String, String)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn get_counter_ext(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    query: Query<FormatQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Counter::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Some(counter) = Counter::get(&path.0, pool.get_ref()).await {
-        counter.as_format(&path.1)
+    if let Some(counter) = Counter::get(&path.0, storage.get_ref()).await {
+        counter.conditional(&req, counter.as_format(&path.1, &query))
     } else {
-        HttpResponse::NotFound().body("")
+        Counter::missing(&path.0, storage.get_ref()).await
     }
 }
 
 #[get("/c/{id}/metrics")]
-async fn get_counter_metrics(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn get_counter_metrics(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Counter::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Some(counter) = Counter::get(&path.0, pool.get_ref()).await {
-        counter.as_openmetrics()
+    if let Some(counter) = Counter::get(&path.0, storage.get_ref()).await {
+        counter.conditional(&req, counter.as_openmetrics(Format::from_request(&req)))
     } else {
-        HttpResponse::NotFound().body("")
+        Counter::missing(&path.0, storage.get_ref()).await
     }
 }
 
+#[get("/c/{id}/history")]
+async fn get_counter_history(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<HistoryQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    counter_history(&req, &path.0, "json", &query, storage.get_ref()).await
+}
+
+#[get("/c/{id}/history.{ext}")]
+async fn get_counter_history_ext(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    query: Query<HistoryQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    counter_history(&req, &path.0, &path.1, &query, storage.get_ref()).await
+}
+
+async fn counter_history(
+    req: &HttpRequest,
+    id: &str,
+    ext: &str,
+    query: &HistoryQuery,
+    storage: &dyn Storage,
+) -> HttpResponse {
+    if !Counter::valid_id(id) {
+        return HttpResponse::BadRequest().body("");
+    }
+    let Some((step, from, to)) = query.range() else {
+        return HttpResponse::BadRequest().body("invalid from, to or step");
+    };
+    if Counter::get(id, storage).await.is_none() {
+        return Counter::missing(id, storage).await;
+    }
+    match storage.history(Kind::Counter, id, step, from, to).await {
+        Ok(buckets) => conditional_body(req, history::as_format(&buckets, ext)),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[get("/c/{id}/chart.svg")]
+async fn get_counter_chart(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<ChartQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Counter::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    let Some(chart) = query.chart(chart::Kind::Bar) else {
+        return HttpResponse::BadRequest().body("invalid window, theme or type");
+    };
+    if Counter::get(&path.0, storage.get_ref()).await.is_none() {
+        return Counter::missing(&path.0, storage.get_ref()).await;
+    }
+    match storage
+        .history(
+            Kind::Counter,
+            &path.0,
+            chart.step(),
+            chart.from(),
+            chart.to(),
+        )
+        .await
+    {
+        Ok(buckets) => conditional_body(
+            &req,
+            HttpResponse::Ok()
+                .insert_header((header::CONTENT_TYPE, "image/svg+xml; charset=utf-8"))
+                .body(chart.render(&buckets)),
+        ),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[get("/c/{id}/events")]
+async fn get_counter_events(
+    path: Path<(String,)>,
+    events: Data<Events>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    subscribe::<Counter>(&path.0, &events, &storage).await
+}
+
 #[post("/c/{id}")]
-async fn post_counter(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn post_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<DeltaQuery>,
+    body: Bytes,
+    salts: Data<Salts>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Counter::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Counter::increment_or_create(&path.0, pool.get_ref()).await {
-        HttpResponse::SeeOther()
+    if let Some(refusal) = Counter::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Some(by) = query.by(&body) else {
+        return HttpResponse::BadRequest().body("invalid body");
+    };
+    if by < 0 {
+        return HttpResponse::BadRequest().body("counters cannot be decremented");
+    }
+    if by != 1 && !matches!(storage.unique(&path.0).await, Ok(false)) {
+        return HttpResponse::BadRequest().body("unique counters count visitors");
+    }
+    let value = if by == 1 {
+        Counter::hit(&path.0, &req, &salts, storage.get_ref()).await
+    } else {
+        Counter::increment_or_create(&path.0, by, storage.get_ref()).await
+    };
+    match value {
+        Ok(Some(i)) => HttpResponse::SeeOther()
             .insert_header((header::LOCATION, format!("/c/{}", path.0)))
             .insert_header(header::ContentType::plaintext())
-            .body(format!("{}", i))
-    } else {
-        HttpResponse::InternalServerError().body("")
+            .body(format!("{}", i)),
+        Ok(None) => HttpResponse::UnprocessableEntity().body("value would overflow"),
+        Err(_) => HttpResponse::InternalServerError().body(""),
     }
 }
 
 #[post("/g")]
-async fn new_gauge(pool: Data<Pool<Sqlite>>) -> impl Responder {
-    if let Ok(gauge) = Gauge::create(pool.get_ref()).await {
-        HttpResponse::SeeOther()
-            .insert_header((header::LOCATION, format!("/g/{}", gauge.id)))
-            .insert_header(header::ContentType::plaintext())
-            .body(gauge.id)
-    } else {
-        HttpResponse::InternalServerError().body("")
+async fn new_gauge(query: Query<CreateQuery>, storage: Data<dyn Storage>) -> impl Responder {
+    let Some(labels) = labels::select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    if query.unique.unwrap_or(false) {
+        return HttpResponse::BadRequest().body("gauges cannot be unique");
+    }
+    let Ok(gauge) = Gauge::create(storage.get_ref()).await else {
+        return HttpResponse::InternalServerError().body("");
+    };
+    let (token, token_hash) = token::generate();
+    let protected = query.protected.unwrap_or(false);
+    if Gauge::claim(&gauge.id, &token_hash, protected, storage.get_ref())
+        .await
+        .is_err()
+    {
+        return HttpResponse::InternalServerError().body("");
     }
+    if !labels.is_empty()
+        && storage
+            .set_labels(Kind::Gauge, &gauge.id, &labels)
+            .await
+            .is_err()
+    {
+        return HttpResponse::InternalServerError().body("");
+    }
+    HttpResponse::SeeOther()
+        .insert_header((header::LOCATION, format!("/g/{}", gauge.id)))
+        .insert_header(("X-Write-Token", token))
+        .insert_header(header::ContentType::plaintext())
+        .body(gauge.id)
 }
 
 #[get("/g/{id}")]
-async fn get_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn get_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Some(gauge) = Gauge::get(&path.0, pool.get_ref()).await {
-        gauge.as_format("txt")
+    if let Some(gauge) = Gauge::get(&path.0, storage.get_ref()).await {
+        gauge.conditional(&req, gauge.as_format("txt", &FormatQuery::default()))
     } else {
-        HttpResponse::NotFound().body("")
+        Gauge::missing(&path.0, storage.get_ref()).await
     }
 }
 
-#[get("/g-/{id}")]
-async fn get_minus_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+#[route("/g-/{id}", method = "GET", method = "HEAD")]
+async fn get_minus_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    bots: Data<BotFilter>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Gauge::decrement_or_create(&path.0, pool.get_ref()).await {
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let hit = if bots.filtered(&req) {
+        bots::current::<Gauge>(&path.0, storage.get_ref()).await
+    } else {
+        Gauge::decrement_or_create(&path.0, 1, storage.get_ref()).await
+    };
+    if let Ok(Some(i)) = hit {
         HttpResponse::SeeOther()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
             .insert_header((header::LOCATION, format!("/g/{}", path.0)))
             .insert_header(header::ContentType::plaintext())
             .body(format!("{}", i))
@@ -674,13 +1204,27 @@ async fn get_minus_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> imp
     }
 }
 
-#[get("/g+/{id}")]
-async fn get_plus_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+#[route("/g+/{id}", method = "GET", method = "HEAD")]
+async fn get_plus_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    bots: Data<BotFilter>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Gauge::increment_or_create(&path.0, pool.get_ref()).await {
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let hit = if bots.filtered(&req) {
+        bots::current::<Gauge>(&path.0, storage.get_ref()).await
+    } else {
+        Gauge::increment_or_create(&path.0, 1, storage.get_ref()).await
+    };
+    if let Ok(Some(i)) = hit {
         HttpResponse::SeeOther()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
             .insert_header((header::LOCATION, format!("/g/{}", path.0)))
             .insert_header(header::ContentType::plaintext())
             .body(format!("{}", i))
@@ -689,66 +1233,243 @@ async fn get_plus_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl
     }
 }
 
-#[get("/g-/{id}.{ext}")]
+#[route("/g-/{id}.{ext}", method = "GET", method = "HEAD")]
 async fn get_minus_gauge_ext(
+    req: HttpRequest,
     path: Path<(String, String)>,
-    pool: Data<Pool<Sqlite>>,
+    query: Query<FormatQuery>,
+    bots: Data<BotFilter>,
+    storage: Data<dyn Storage>,
 ) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Gauge::decrement_or_create(&path.0, pool.get_ref()).await {
-        Gauge::new(&path.0, i).as_format(&path.1)
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let hit = if bots.filtered(&req) {
+        bots::current::<Gauge>(&path.0, storage.get_ref()).await
+    } else {
+        Gauge::decrement_or_create(&path.0, 1, storage.get_ref()).await
+    };
+    if let Ok(Some(i)) = hit {
+        uncached(Gauge::new(&path.0, i).as_format(&path.1, &query))
     } else {
         HttpResponse::NotFound().body("")
     }
 }
 
-#[get("/g+/{id}.{ext}")]
+#[route("/g+/{id}.{ext}", method = "GET", method = "HEAD")]
 async fn get_plus_gauge_ext(
+    req: HttpRequest,
     path: Path<(String, String)>,
-    pool: Data<Pool<Sqlite>>,
+    query: Query<FormatQuery>,
+    bots: Data<BotFilter>,
+    storage: Data<dyn Storage>,
 ) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Gauge::increment_or_create(&path.0, pool.get_ref()).await {
-        Gauge::new(&path.0, i).as_format(&path.1)
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let hit = if bots.filtered(&req) {
+        bots::current::<Gauge>(&path.0, storage.get_ref()).await
+    } else {
+        Gauge::increment_or_create(&path.0, 1, storage.get_ref()).await
+    };
+    if let Ok(Some(i)) = hit {
+        uncached(Gauge::new(&path.0, i).as_format(&path.1, &query))
     } else {
         HttpResponse::NotFound().body("")
     }
 }
 
 #[get("/g/{id}.{ext}")]
-async fn get_gauge_ext(path: Path<(String, String)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn get_gauge_ext(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    query: Query<FormatQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Some(gauge) = Gauge::get(&path.0, pool.get_ref()).await {
-        gauge.as_format(&path.1)
+    if let Some(gauge) = Gauge::get(&path.0, storage.get_ref()).await {
+        gauge.conditional(&req, gauge.as_format(&path.1, &query))
     } else {
-        HttpResponse::NotFound().body("")
+        Gauge::missing(&path.0, storage.get_ref()).await
     }
 }
 
 #[get("/g/{id}/metrics")]
-async fn get_gauge_metrics(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn get_gauge_metrics(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Some(gauge) = Gauge::get(&path.0, pool.get_ref()).await {
-        gauge.as_openmetrics()
+    if let Some(gauge) = Gauge::get(&path.0, storage.get_ref()).await {
+        gauge.conditional(&req, gauge.as_openmetrics(Format::from_request(&req)))
     } else {
-        HttpResponse::NotFound().body("")
+        Gauge::missing(&path.0, storage.get_ref()).await
+    }
+}
+
+#[get("/g/{id}/history")]
+async fn get_gauge_history(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<HistoryQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    gauge_history(&req, &path.0, "json", &query, storage.get_ref()).await
+}
+
+#[get("/g/{id}/history.{ext}")]
+async fn get_gauge_history_ext(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    query: Query<HistoryQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    gauge_history(&req, &path.0, &path.1, &query, storage.get_ref()).await
+}
+
+async fn gauge_history(
+    req: &HttpRequest,
+    id: &str,
+    ext: &str,
+    query: &HistoryQuery,
+    storage: &dyn Storage,
+) -> HttpResponse {
+    if !Gauge::valid_id(id) {
+        return HttpResponse::BadRequest().body("");
+    }
+    let Some((step, from, to)) = query.range() else {
+        return HttpResponse::BadRequest().body("invalid from, to or step");
+    };
+    if Gauge::get(id, storage).await.is_none() {
+        return Gauge::missing(id, storage).await;
+    }
+    match storage.history(Kind::Gauge, id, step, from, to).await {
+        Ok(buckets) => conditional_body(req, history::as_format(&buckets, ext)),
+        Err(_) => HttpResponse::InternalServerError().body(""),
     }
 }
 
+#[get("/g/{id}/chart.svg")]
+async fn get_gauge_chart(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<ChartQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    let Some(chart) = query.chart(chart::Kind::Line) else {
+        return HttpResponse::BadRequest().body("invalid window, theme or type");
+    };
+    if Gauge::get(&path.0, storage.get_ref()).await.is_none() {
+        return Gauge::missing(&path.0, storage.get_ref()).await;
+    }
+    match storage
+        .history(Kind::Gauge, &path.0, chart.step(), chart.from(), chart.to())
+        .await
+    {
+        Ok(buckets) => conditional_body(
+            &req,
+            HttpResponse::Ok()
+                .insert_header((header::CONTENT_TYPE, "image/svg+xml; charset=utf-8"))
+                .body(chart.render(&buckets)),
+        ),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[get("/g/{id}/events")]
+async fn get_gauge_events(
+    path: Path<(String,)>,
+    events: Data<Events>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    subscribe::<Gauge>(&path.0, &events, &storage).await
+}
+
 #[post("/g/{id}")]
-async fn post_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+async fn post_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<DeltaQuery>,
+    body: Bytes,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Gauge::increment_or_create(&path.0, pool.get_ref()).await {
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Some(by) = query.by(&body) else {
+        return HttpResponse::BadRequest().body("invalid body");
+    };
+    match Gauge::increment_or_create(&path.0, by, storage.get_ref()).await {
+        Ok(Some(i)) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/g/{}", path.0)))
+            .insert_header(header::ContentType::plaintext())
+            .body(format!("{}", i)),
+        Ok(None) => HttpResponse::UnprocessableEntity().body("value would overflow"),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[post("/g-/{id}")]
+async fn post_minus_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    query: Query<DeltaQuery>,
+    body: Bytes,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Some(by) = query.by(&body) else {
+        return HttpResponse::BadRequest().body("invalid body");
+    };
+    match Gauge::decrement_or_create(&path.0, by, storage.get_ref()).await {
+        Ok(Some(i)) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/g/{}", path.0)))
+            .insert_header(header::ContentType::plaintext())
+            .body(format!("{}", i)),
+        Ok(None) => HttpResponse::UnprocessableEntity().body("value would overflow"),
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[put("/g/{id}")]
+async fn put_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    body: String,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Ok(value) = body.trim().parse() else {
+        return HttpResponse::BadRequest().body("body must be an integer");
+    };
+    if let Ok(i) = Gauge::set_or_create(&path.0, value, storage.get_ref()).await {
         HttpResponse::SeeOther()
             .insert_header((header::LOCATION, format!("/g/{}", path.0)))
             .insert_header(header::ContentType::plaintext())
@@ -758,17 +1479,254 @@ async fn post_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Res
     }
 }
 
-#[post("/g-/{id}")]
-async fn post_minus_gauge(path: Path<(String,)>, pool: Data<Pool<Sqlite>>) -> impl Responder {
+#[put("/g/{id}.{ext}")]
+async fn put_gauge_ext(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    query: Query<FormatQuery>,
+    body: String,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Ok(value) = body.trim().parse() else {
+        return HttpResponse::BadRequest().body("body must be an integer");
+    };
+    if let Ok(i) = Gauge::set_or_create(&path.0, value, storage.get_ref()).await {
+        Gauge::new(&path.0, i).as_format(&path.1, &query)
+    } else {
+        HttpResponse::InternalServerError().body("")
+    }
+}
+
+#[route("/g=/{id}", method = "GET", method = "POST")]
+async fn set_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    set: Query<SetQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
     if !Gauge::valid_id(&path.0) {
         return HttpResponse::BadRequest().body("");
     }
-    if let Ok(i) = Gauge::decrement_or_create(&path.0, pool.get_ref()).await {
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Some(value) = set.value else {
+        return HttpResponse::BadRequest().body("missing ?value=");
+    };
+    if let Ok(i) = Gauge::set_or_create(&path.0, value, storage.get_ref()).await {
         HttpResponse::SeeOther()
+            .insert_header((header::CACHE_CONTROL, "no-store"))
             .insert_header((header::LOCATION, format!("/g/{}", path.0)))
             .insert_header(header::ContentType::plaintext())
             .body(format!("{}", i))
     } else {
-        HttpResponse::NotFound().body("")
+        HttpResponse::InternalServerError().body("")
+    }
+}
+
+#[route("/g=/{id}.{ext}", method = "GET", method = "POST")]
+async fn set_gauge_ext(
+    req: HttpRequest,
+    path: Path<(String, String)>,
+    set: Query<SetQuery>,
+    query: Query<FormatQuery>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if let Some(refusal) = Gauge::refuse_write(&path.0, &req, storage.get_ref()).await {
+        return refusal;
+    }
+    let Some(value) = set.value else {
+        return HttpResponse::BadRequest().body("missing ?value=");
+    };
+    if let Ok(i) = Gauge::set_or_create(&path.0, value, storage.get_ref()).await {
+        uncached(Gauge::new(&path.0, i).as_format(&path.1, &query))
+    } else {
+        HttpResponse::InternalServerError().body("")
+    }
+}
+
+#[post("/c/{id}/protect")]
+async fn protect_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    set_protected::<Counter>(&req, &path.0, true, storage.get_ref()).await
+}
+
+#[post("/c/{id}/unprotect")]
+async fn unprotect_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    set_protected::<Counter>(&req, &path.0, false, storage.get_ref()).await
+}
+
+#[post("/g/{id}/protect")]
+async fn protect_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    set_protected::<Gauge>(&req, &path.0, true, storage.get_ref()).await
+}
+
+#[post("/g/{id}/unprotect")]
+async fn unprotect_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    set_protected::<Gauge>(&req, &path.0, false, storage.get_ref()).await
+}
+
+/// Streams the value of a counter or gauge as Server-Sent Events, starting
+/// with the current value and then each new one as it changes.
+async fn subscribe<T: CounterLike>(
+    id: &str,
+    events: &Events,
+    storage: &Data<dyn Storage>,
+) -> HttpResponse
+where
+    HttpDate: for<'a> From<&'a T>,
+{
+    if !T::valid_id(id) {
+        return HttpResponse::BadRequest().body("");
+    }
+    let Some(counter) = T::get(id, storage.get_ref()).await else {
+        return T::missing(id, storage.get_ref()).await;
+    };
+    let Some(subscription) = events.subscribe() else {
+        return HttpResponse::ServiceUnavailable().body("too many connections");
+    };
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, "text/event-stream"))
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoCache]))
+        // Compressing would hold messages back until enough pile up.
+        .insert_header(header::ContentEncoding::Identity)
+        .streaming(events::stream(
+            subscription,
+            storage.clone().into_inner(),
+            T::KIND,
+            id.to_owned(),
+            counter.value(),
+        ))
+}
+
+async fn set_protected<T: CounterLike>(
+    req: &HttpRequest,
+    id: &str,
+    protected: bool,
+    storage: &dyn Storage,
+) -> HttpResponse
+where
+    HttpDate: for<'a> From<&'a T>,
+{
+    if !T::valid_id(id) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if !T::is_owner(id, req, storage).await {
+        return HttpResponse::Forbidden().body("");
+    }
+    if T::set_protected(id, protected, storage).await.is_ok() {
+        HttpResponse::NoContent().finish()
+    } else {
+        HttpResponse::InternalServerError().body("")
+    }
+}
+
+#[delete("/c/{id}")]
+async fn delete_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    admin: Data<AdminToken>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Counter::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if !Counter::may_manage(&path.0, &req, &admin, storage.get_ref()).await {
+        return HttpResponse::Forbidden().body("");
+    }
+    match Counter::delete(&path.0, storage.get_ref()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => Counter::missing(&path.0, storage.get_ref()).await,
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[post("/c/{id}/reset")]
+async fn reset_counter(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    admin: Data<AdminToken>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Counter::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if !Counter::may_manage(&path.0, &req, &admin, storage.get_ref()).await {
+        return HttpResponse::Forbidden().body("");
+    }
+    match Counter::reset(&path.0, storage.get_ref()).await {
+        Ok(true) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/c/{}", path.0)))
+            .insert_header(header::ContentType::plaintext())
+            .body("0"),
+        Ok(false) => Counter::missing(&path.0, storage.get_ref()).await,
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[delete("/g/{id}")]
+async fn delete_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    admin: Data<AdminToken>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if !Gauge::may_manage(&path.0, &req, &admin, storage.get_ref()).await {
+        return HttpResponse::Forbidden().body("");
+    }
+    match Gauge::delete(&path.0, storage.get_ref()).await {
+        Ok(true) => HttpResponse::NoContent().finish(),
+        Ok(false) => Gauge::missing(&path.0, storage.get_ref()).await,
+        Err(_) => HttpResponse::InternalServerError().body(""),
+    }
+}
+
+#[post("/g/{id}/reset")]
+async fn reset_gauge(
+    req: HttpRequest,
+    path: Path<(String,)>,
+    admin: Data<AdminToken>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    if !Gauge::valid_id(&path.0) {
+        return HttpResponse::BadRequest().body("");
+    }
+    if !Gauge::may_manage(&path.0, &req, &admin, storage.get_ref()).await {
+        return HttpResponse::Forbidden().body("");
+    }
+    match Gauge::reset(&path.0, storage.get_ref()).await {
+        Ok(true) => HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/g/{}", path.0)))
+            .insert_header(header::ContentType::plaintext())
+            .body("0"),
+        Ok(false) => Gauge::missing(&path.0, storage.get_ref()).await,
+        Err(_) => HttpResponse::InternalServerError().body(""),
     }
 }
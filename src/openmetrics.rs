@@ -0,0 +1,256 @@
+use crate::{
+    blocklist::Blocklist,
+    labels,
+    storage::{Kind, Record, Storage},
+};
+use actix_web::{
+    get,
+    http::header,
+    web::{Data, Query},
+    HttpMessage, HttpRequest, HttpResponse, Responder,
+};
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::fmt::Write;
+
+const DEFAULT_LIMIT: i64 = 1000;
+const MAX_LIMIT: i64 = 1000;
+
+/// A text format metrics can be exposed in, picked from the `Accept` header.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Format {
+    OpenMetrics,
+    /// The Prometheus 0.0.4 text format, for older scrapers.
+    Prometheus,
+}
+
+impl Format {
+    /// Picks the format the client ranks highest, preferring OpenMetrics
+    /// when it has no preference.
+    pub fn from_request(req: &HttpRequest) -> Self {
+        let Some(accept) = req.get_header::<header::Accept>() else {
+            return Self::OpenMetrics;
+        };
+        for mime in accept.ranked() {
+            match (mime.type_().as_str(), mime.subtype().as_str()) {
+                ("application", "openmetrics-text") => return Self::OpenMetrics,
+                ("text", "plain") => return Self::Prometheus,
+                ("*" | "application", "*") => return Self::OpenMetrics,
+                ("text", "*") => return Self::Prometheus,
+                _ => continue,
+            }
+        }
+        Self::OpenMetrics
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::OpenMetrics => "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            Self::Prometheus => "text/plain; version=0.0.4; charset=utf-8",
+        }
+    }
+}
+
+/// Writes the metric family for counters or gauges, with a sample labelled
+/// by `id` for each one.
+pub fn family<'a>(
+    out: &mut String,
+    format: Format,
+    kind: Kind,
+    samples: impl IntoIterator<Item = (&'a str, i64)>,
+) {
+    let (name, suffix, kind, help) = match kind {
+        Kind::Counter => (
+            "tickrs_counter",
+            "_total",
+            "counter",
+            "Value of a tick.rs counter.",
+        ),
+        Kind::Gauge => ("tickrs_gauge", "", "gauge", "Value of a tick.rs gauge."),
+    };
+    // OpenMetrics names the family without the suffix, Prometheus with it.
+    let family = match format {
+        Format::OpenMetrics => name.to_owned(),
+        Format::Prometheus => format!("{}{}", name, suffix),
+    };
+    let _ = writeln!(out, "# TYPE {} {}", family, kind);
+    let _ = writeln!(out, "# HELP {} {}", family, help);
+    for (id, value) in samples {
+        let _ = writeln!(out, "{}{}{{id=\"{}\"}} {}", name, suffix, escape(id), value);
+    }
+}
+
+/// Ends the exposition. Only OpenMetrics has an explicit end.
+pub fn finish(out: &mut String, format: Format) {
+    if format == Format::OpenMetrics {
+        out.push_str("# EOF\n");
+    }
+}
+
+fn samples(records: &[Record]) -> impl Iterator<Item = (&str, i64)> {
+    records
+        .iter()
+        .map(|record| (record.id.as_str(), record.value))
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', r"\\")
+        .replace('"', r#"\""#)
+        .replace('\n', r"\n")
+}
+
+#[derive(Deserialize)]
+struct ScrapeQuery {
+    prefix: Option<String>,
+    label: Option<String>,
+    after: Option<String>,
+    limit: Option<i64>,
+}
+
+/// Every counter and gauge whose ID starts with `prefix`, as one document.
+/// At most `limit` of each are included, ordered by ID. When there are more,
+/// a `Link` header points at the next page.
+#[get("/_metrics")]
+async fn get_metrics(
+    req: HttpRequest,
+    query: Query<ScrapeQuery>,
+    blocklist: Data<Blocklist>,
+    storage: Data<dyn Storage>,
+) -> impl Responder {
+    let prefix = query.prefix.as_deref().unwrap_or("");
+    let after = query.after.as_deref().unwrap_or("");
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+    let Some(labels) = labels::select(query.label.as_deref()) else {
+        return HttpResponse::BadRequest().body("invalid label");
+    };
+    let (Ok(mut counters), Ok(mut gauges)) = (
+        storage
+            .list(Kind::Counter, prefix, &labels, after, limit)
+            .await,
+        storage
+            .list(Kind::Gauge, prefix, &labels, after, limit)
+            .await,
+    ) else {
+        return HttpResponse::InternalServerError().body("");
+    };
+
+    // If either list was cut short, the page ends at the earliest place it
+    // was cut, so the next page can pick up both from there.
+    let next = [&counters, &gauges]
+        .into_iter()
+        .filter(|records| records.len() as i64 == limit)
+        .filter_map(|records| records.last().map(|record| record.id.clone()))
+        .min();
+    if let Some(next) = &next {
+        counters.retain(|record| record.id <= *next);
+        gauges.retain(|record| record.id <= *next);
+    }
+    counters.retain(|record| !blocklist.is_blocked(&record.id));
+    gauges.retain(|record| !blocklist.is_blocked(&record.id));
+
+    let format = Format::from_request(&req);
+    let mut body = String::new();
+    family(&mut body, format, Kind::Counter, samples(&counters));
+    family(&mut body, format, Kind::Gauge, samples(&gauges));
+    finish(&mut body, format);
+
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header((header::CONTENT_TYPE, format.content_type()))
+        .insert_header((header::VARY, "Accept"));
+    if let Some(next) = next {
+        let mut link = format!(
+            "/_metrics?prefix={}&after={}&limit={}",
+            utf8_percent_encode(prefix, NON_ALPHANUMERIC),
+            utf8_percent_encode(&next, NON_ALPHANUMERIC),
+            limit
+        );
+        // An empty selector is malformed, so it's left out when not given.
+        if let Some(label) = &query.label {
+            let _ = write!(
+                link,
+                "&label={}",
+                utf8_percent_encode(label, NON_ALPHANUMERIC)
+            );
+        }
+        response.insert_header((header::LINK, format!("<{}>; rel=\"next\"", link)));
+    }
+    response.body(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn format(accept: Option<&str>) -> Format {
+        let mut req = TestRequest::default();
+        if let Some(accept) = accept {
+            req = req.insert_header((header::ACCEPT, accept));
+        }
+        Format::from_request(&req.to_http_request())
+    }
+
+    #[test]
+    fn escapes_label_values() {
+        assert_eq!(escape("plain"), "plain");
+        assert_eq!(escape(r#"say "hi""#), r#"say \"hi\""#);
+        assert_eq!(escape("two\nlines"), r"two\nlines");
+        assert_eq!(escape(r"back\slash"), r"back\\slash");
+        // Backslashes are escaped first, so they aren't escaped twice.
+        assert_eq!(escape("\\\"\n"), r#"\\\"\n"#);
+    }
+
+    #[test]
+    fn writes_openmetrics_counters() {
+        let mut out = String::new();
+        family(
+            &mut out,
+            Format::OpenMetrics,
+            Kind::Counter,
+            [("a\"b", 3), ("c", 0)],
+        );
+        finish(&mut out, Format::OpenMetrics);
+        assert_eq!(
+            out,
+            "# TYPE tickrs_counter counter\n\
+             # HELP tickrs_counter Value of a tick.rs counter.\n\
+             tickrs_counter_total{id=\"a\\\"b\"} 3\n\
+             tickrs_counter_total{id=\"c\"} 0\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn writes_prometheus_gauges() {
+        let mut out = String::new();
+        family(&mut out, Format::Prometheus, Kind::Gauge, [("q", -2)]);
+        finish(&mut out, Format::Prometheus);
+        assert_eq!(
+            out,
+            "# TYPE tickrs_gauge gauge\n\
+             # HELP tickrs_gauge Value of a tick.rs gauge.\n\
+             tickrs_gauge{id=\"q\"} -2\n"
+        );
+    }
+
+    #[test]
+    fn names_prometheus_counter_families_with_their_suffix() {
+        let mut out = String::new();
+        family(&mut out, Format::Prometheus, Kind::Counter, []);
+        assert!(out.starts_with("# TYPE tickrs_counter_total counter\n"));
+    }
+
+    #[test]
+    fn negotiates_formats() {
+        assert!(format(None) == Format::OpenMetrics);
+        assert!(format(Some("*/*")) == Format::OpenMetrics);
+        assert!(format(Some("application/openmetrics-text; version=1.0.0")) == Format::OpenMetrics);
+        assert!(format(Some("text/plain; version=0.0.4")) == Format::Prometheus);
+        assert!(
+            format(Some("application/openmetrics-text;q=0.5, text/plain")) == Format::Prometheus
+        );
+        assert!(format(Some("image/png")) == Format::OpenMetrics);
+    }
+}
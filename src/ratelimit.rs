@@ -0,0 +1,249 @@
+use crate::blocklist::path_id;
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::{header, Method},
+    middleware::Next,
+    web::Data,
+    Error, HttpRequest, HttpResponse,
+};
+use prometheus::{IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry};
+use std::{
+    collections::HashMap,
+    env,
+    net::IpAddr,
+    sync::{LazyLock, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Upper bound on buckets held for one kind of key, so spraying addresses or
+/// IDs can't grow the map without limit.
+const MAX_BUCKETS: usize = 100_000;
+
+/// Reverse proxies trusted to say who the client is, from the comma-separated
+/// addresses in `TRUSTED_PROXIES`.
+static TRUSTED_PROXIES: LazyLock<Vec<IpAddr>> = LazyLock::new(|| {
+    env::var("TRUSTED_PROXIES")
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|addr| addr.trim().parse().ok())
+        .collect()
+});
+
+/// The client's address. `Forwarded` and `X-Forwarded-For` are only believed
+/// when the connection comes from a trusted proxy, as anyone can send them.
+pub fn client_addr(req: &HttpRequest) -> Option<String> {
+    let peer = req.peer_addr()?.ip();
+    if TRUSTED_PROXIES.contains(&peer) {
+        if let Some(addr) = req.connection_info().realip_remote_addr() {
+            return Some(addr.to_owned());
+        }
+    }
+    Some(peer.to_string())
+}
+
+/// How quickly a bucket refills, and how many tokens it holds when full.
+#[derive(Clone, Copy)]
+struct Limit {
+    per_second: f64,
+    burst: f64,
+}
+
+impl Limit {
+    /// Reads `{prefix}_PER_MINUTE` and `{prefix}_BURST`, which defaults to a
+    /// minute's worth. Without a rate there's no limit.
+    fn from_env(prefix: &str) -> Option<Self> {
+        let per_minute: f64 = env::var(format!("{}_PER_MINUTE", prefix))
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|n: &f64| *n > 0.0)?;
+        let burst = env::var(format!("{}_BURST", prefix))
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .filter(|n: &f64| *n >= 1.0)
+            .unwrap_or(per_minute.max(1.0));
+        Some(Self {
+            per_second: per_minute / 60.0,
+            burst,
+        })
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets for one kind of key. They're only ever held in memory, so
+/// client addresses are never written anywhere.
+struct Buckets {
+    limit: Limit,
+    buckets: Mutex<HashMap<String, Bucket>>,
+    rejected: IntCounter,
+    tracked: IntGauge,
+}
+
+impl Buckets {
+    fn new(limit: Limit, key: &str, rejected: &IntCounterVec, tracked: &IntGaugeVec) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::default(),
+            rejected: rejected.with_label_values(&[key]),
+            tracked: tracked.with_label_values(&[key]),
+        }
+    }
+
+    fn refilled(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.limit.per_second).min(self.limit.burst)
+    }
+
+    /// Takes a token for `key`, or says how long until there's one to take.
+    fn take(&self, key: &str, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if !buckets.contains_key(key) {
+            self.make_room(&mut buckets, now);
+        }
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.limit.burst,
+            updated: now,
+        });
+        bucket.tokens = self.refilled(bucket, now);
+        bucket.updated = now;
+        let taken = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            self.rejected.inc();
+            Err(Duration::from_secs_f64(
+                (1.0 - bucket.tokens) / self.limit.per_second,
+            ))
+        };
+        self.tracked.set(buckets.len() as i64);
+        taken
+    }
+
+    /// Frees up space for a new bucket once there are `MAX_BUCKETS`, first by
+    /// dropping refilled ones, then the quarter that have been quiet longest.
+    fn make_room(&self, buckets: &mut HashMap<String, Bucket>, now: Instant) {
+        if buckets.len() < MAX_BUCKETS {
+            return;
+        }
+        buckets.retain(|_, bucket| self.refilled(bucket, now) < self.limit.burst);
+        if buckets.len() < MAX_BUCKETS {
+            return;
+        }
+        let mut updated: Vec<_> = buckets.values().map(|bucket| bucket.updated).collect();
+        let cutoff = *updated.select_nth_unstable(MAX_BUCKETS / 4).1;
+        buckets.retain(|_, bucket| bucket.updated > cutoff);
+    }
+
+    /// Drops buckets that have refilled, as they're no different from new ones.
+    fn prune(&self, now: Instant) {
+        let mut buckets = self.buckets.lock().unwrap();
+        buckets.retain(|_, bucket| self.refilled(bucket, now) < self.limit.burst);
+        self.tracked.set(buckets.len() as i64);
+    }
+}
+
+/// Limits on requests that change something, per client address from
+/// `RATE_LIMIT_CLIENT_*` and per counter or gauge from `RATE_LIMIT_ID_*`.
+pub struct RateLimiter {
+    client: Option<Buckets>,
+    id: Option<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn from_env(registry: &Registry) -> Self {
+        let rejected = IntCounterVec::new(
+            Opts::new(
+                "tickrs_rate_limited_total",
+                "Requests rejected by the rate limiter",
+            ),
+            &["key"],
+        )
+        .unwrap();
+        let tracked = IntGaugeVec::new(
+            Opts::new(
+                "tickrs_rate_limit_buckets",
+                "Clients or IDs the rate limiter is tracking",
+            ),
+            &["key"],
+        )
+        .unwrap();
+        registry.register(Box::new(rejected.clone())).unwrap();
+        registry.register(Box::new(tracked.clone())).unwrap();
+
+        Self {
+            client: Limit::from_env("RATE_LIMIT_CLIENT")
+                .map(|limit| Buckets::new(limit, "client", &rejected, &tracked)),
+            id: Limit::from_env("RATE_LIMIT_ID")
+                .map(|limit| Buckets::new(limit, "id", &rejected, &tracked)),
+        }
+    }
+
+    /// Takes a token for the client and for the counter or gauge it's
+    /// changing, where they're limited, or says how long until there's one
+    /// to take.
+    pub fn take(&self, client: Option<&str>, id: Option<&str>) -> Result<(), Duration> {
+        let now = Instant::now();
+        match (&self.client, client) {
+            (Some(buckets), Some(client)) => buckets.take(client, now),
+            _ => Ok(()),
+        }
+        .and_then(|()| match (&self.id, id) {
+            (Some(buckets), Some(id)) => buckets.take(id, now),
+            _ => Ok(()),
+        })
+    }
+
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.client
+            .iter()
+            .chain(&self.id)
+            .for_each(|b| b.prune(now));
+    }
+}
+
+/// Whether a request can change a value or create one. Reads are cheap and
+/// embedded everywhere, so they aren't limited.
+pub fn mutates(req: &ServiceRequest) -> bool {
+    if req.path().starts_with("/_admin") {
+        return false;
+    }
+    match *req.method() {
+        Method::GET | Method::HEAD => {
+            let route = req.path().trim_start_matches('/').split('/').next();
+            matches!(route, Some("c+" | "g+" | "g-" | "g="))
+        }
+        _ => true,
+    }
+}
+
+/// Middleware that answers `429 Too Many Requests` once a client, or the
+/// counter or gauge it's changing, runs out of tokens.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let limiter = match req.app_data::<Data<RateLimiter>>() {
+        Some(limiter) if mutates(&req) => limiter.clone(),
+        _ => return Ok(next.call(req).await?.map_into_left_body()),
+    };
+    let client = client_addr(req.request());
+    let id = path_id(req.path());
+    match limiter.take(client.as_deref(), id.as_deref()) {
+        Ok(()) => Ok(next.call(req).await?.map_into_left_body()),
+        Err(wait) => {
+            let response = HttpResponse::TooManyRequests()
+                .insert_header((
+                    header::RETRY_AFTER,
+                    wait.as_secs_f64().ceil().max(1.0) as u64,
+                ))
+                .body("");
+            Ok(req.into_response(response).map_into_right_body())
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{
+    blocklist::Blocklist, ratelimit::RateLimiter, storage::Storage, Counter, CounterLike, Gauge,
+};
+use actix_web::{rt::net::UdpSocket, web::Data};
+use std::sync::Arc;
+
+/// Large enough for any UDP datagram.
+const MAX_PACKET: usize = 65536;
+
+/// A single StatsD line, e.g. `name:1|c`, `name:5|g` or `name:-1|g`.
+enum Metric<'a> {
+    Count(&'a str, i64),
+    Gauge(&'a str, i64),
+    GaugeDelta(&'a str, i64),
+}
+
+impl<'a> Metric<'a> {
+    /// Parses a line, returning `None` for anything malformed or for metric
+    /// types other than counters and gauges.
+    fn parse(line: &'a str) -> Option<Self> {
+        let (name, rest) = line.split_once(':')?;
+        let mut fields = rest.split('|');
+        let value = fields.next()?;
+        let kind = fields.next()?;
+        let rate = match fields.next().and_then(|field| field.strip_prefix('@')) {
+            Some(rate) => rate.parse::<f64>().ok().filter(|r| *r > 0.0 && *r <= 1.0)?,
+            None => 1.0,
+        };
+        match kind {
+            "c" => {
+                let value = value.parse::<i64>().ok()?;
+                // Sampled counts stand in for more hits than were sent.
+                Some(Self::Count(name, (value as f64 / rate).round() as i64))
+            }
+            "g" if value.starts_with(['+', '-']) => {
+                Some(Self::GaugeDelta(name, value.parse().ok()?))
+            }
+            "g" => Some(Self::Gauge(name, value.parse().ok()?)),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'a str {
+        match self {
+            Self::Count(name, _) | Self::Gauge(name, _) | Self::GaugeDelta(name, _) => name,
+        }
+    }
+
+    async fn apply(&self, storage: &dyn Storage) {
+        // Deleted counters stay deleted, and there's no way to send a write
+        // token, so protected ones are left alone.
+        let (deleted, owner) = match self {
+            Self::Count(id, _) => (
+                Counter::deleted(id, storage).await,
+                Counter::owner(id, storage).await,
+            ),
+            Self::Gauge(id, _) | Self::GaugeDelta(id, _) => (
+                Gauge::deleted(id, storage).await,
+                Gauge::owner(id, storage).await,
+            ),
+        };
+        if deleted {
+            return;
+        }
+        match owner {
+            Ok(Some(owner)) if owner.protected => return,
+            Err(_) => return,
+            _ => {}
+        }
+        // Unique counters only count visitors to the web server.
+        if let Self::Count(id, _) = self {
+            if !matches!(storage.unique(id).await, Ok(false)) {
+                return;
+            }
+        }
+        // Errors are dropped, as there's nobody to report them to.
+        match *self {
+            Self::Count(id, by) => {
+                let _ = Counter::increment_or_create(id, by, storage).await;
+            }
+            Self::Gauge(id, value) => {
+                let _ = Gauge::set_or_create(id, value, storage).await;
+            }
+            Self::GaugeDelta(id, by) => {
+                let _ = Gauge::increment_or_create(id, by, storage).await;
+            }
+        }
+    }
+}
+
+/// Applies StatsD counters and gauges sent to `socket` until the server
+/// stops. Lines with invalid or blocked names, or for IDs over their rate
+/// limit, are dropped.
+pub async fn listen(
+    socket: UdpSocket,
+    storage: Arc<dyn Storage>,
+    blocklist: Data<Blocklist>,
+    limiter: Data<RateLimiter>,
+) {
+    let mut buf = vec![0; MAX_PACKET];
+    loop {
+        let Ok(len) = socket.recv(&mut buf).await else {
+            continue;
+        };
+        for line in buf[..len].split(|b| *b == b'\n') {
+            let Some(metric) = std::str::from_utf8(line)
+                .ok()
+                .and_then(|line| Metric::parse(line.trim()))
+            else {
+                continue;
+            };
+            let id = metric.name();
+            let valid = match metric {
+                Metric::Count(..) => Counter::valid_id(id),
+                Metric::Gauge(..) | Metric::GaugeDelta(..) => Gauge::valid_id(id),
+            };
+            if valid && !blocklist.is_blocked(id) && limiter.take(None, Some(id)).is_ok() {
+                metric.apply(storage.as_ref()).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_counts_and_gauges() {
+        assert!(matches!(
+            Metric::parse("hits:1|c"),
+            Some(Metric::Count("hits", 1))
+        ));
+        assert!(matches!(
+            Metric::parse("queue:5|g"),
+            Some(Metric::Gauge("queue", 5))
+        ));
+        assert!(matches!(
+            Metric::parse("queue:-1|g"),
+            Some(Metric::GaugeDelta("queue", -1))
+        ));
+        assert!(matches!(
+            Metric::parse("queue:+3|g"),
+            Some(Metric::GaugeDelta("queue", 3))
+        ));
+    }
+
+    #[test]
+    fn scales_sampled_counts() {
+        assert!(matches!(
+            Metric::parse("hits:1|c|@0.1"),
+            Some(Metric::Count("hits", 10))
+        ));
+        assert!(matches!(
+            Metric::parse("hits:3|c|@1"),
+            Some(Metric::Count("hits", 3))
+        ));
+    }
+
+    #[test]
+    fn rejects_bad_sample_rates() {
+        for line in [
+            "hits:1|c|@0",
+            "hits:1|c|@-0.5",
+            "hits:1|c|@1.5",
+            "hits:1|c|@abc",
+            "hits:1|c|@",
+            "hits:1|c|@NaN",
+        ] {
+            assert!(Metric::parse(line).is_none(), "{}", line);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_lines() {
+        for line in [
+            "",
+            "hits",
+            "hits:1",
+            "hits:1|ms",
+            "hits:1|h",
+            "hits:abc|c",
+            "hits:1.5|c",
+            "queue:|g",
+            "queue:+|g",
+            "queue:--1|g",
+            "queue:+-1|g",
+            "queue:1.5|g",
+        ] {
+            assert!(Metric::parse(line).is_none(), "{}", line);
+        }
+    }
+}
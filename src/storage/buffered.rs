@@ -0,0 +1,498 @@
+use super::{Kind, Op, Record, Stats, Storage};
+use crate::{
+    blocklist::Block,
+    history::{Bucket, Step},
+    idempotency::{Remembered, Response},
+    labels::Labels,
+    token::Owner,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::{
+    collections::HashMap,
+    env, mem,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+const DEFAULT_MAX_IDS: usize = 10_000;
+
+/// How the write buffer is configured, from the environment:
+///
+/// - `WRITE_BUFFER_MS` turns buffering on, flushing every that many
+///   milliseconds. At most this long's worth of increments can be lost if the
+///   process dies without shutting down cleanly. 0 leaves buffering off.
+/// - `WRITE_BUFFER_MAX_IDS` flushes early once this many IDs are buffered.
+pub struct Config {
+    pub interval: Duration,
+    pub max_ids: usize,
+}
+
+impl Config {
+    pub fn from_env() -> Option<Self> {
+        let interval = env::var("WRITE_BUFFER_MS")
+            .ok()?
+            .parse()
+            .ok()
+            .filter(|n: &u64| *n > 0)?;
+        let max_ids = env::var("WRITE_BUFFER_MAX_IDS")
+            .ok()
+            .and_then(|n| n.parse().ok())
+            .unwrap_or(DEFAULT_MAX_IDS);
+        Some(Self {
+            interval: Duration::from_millis(interval),
+            max_ids,
+        })
+    }
+}
+
+/// A buffered ID: the value last read from or written to the backend, plus
+/// the increments applied since. `flushing` holds increments that are being
+/// written out right now. `updated_at` is when the last one was buffered.
+struct Pending {
+    base: i64,
+    flushing: i64,
+    delta: i64,
+    updated_at: DateTime<Utc>,
+}
+
+impl Pending {
+    fn value(&self) -> i64 {
+        self.base + self.flushing + self.delta
+    }
+}
+
+/// Accumulates increments in memory and writes them to `inner` in a single
+/// transaction on `flush`, so hot counters don't take the writer lock on every
+/// hit. Anything other than an increment flushes first and goes straight to
+/// `inner`.
+pub struct Buffered {
+    inner: Arc<dyn Storage>,
+    pending: Mutex<HashMap<(Kind, String), Pending>>,
+    flushing: tokio::sync::Mutex<()>,
+    /// Shared by increments, and taken alone by writes that bypass the
+    /// buffer, so nothing is buffered while one runs and then forgotten.
+    bypassing: tokio::sync::RwLock<()>,
+    max_ids: usize,
+}
+
+impl Buffered {
+    pub fn new(inner: Arc<dyn Storage>, config: &Config) -> Self {
+        Self {
+            inner,
+            pending: Mutex::default(),
+            flushing: tokio::sync::Mutex::default(),
+            bypassing: tokio::sync::RwLock::default(),
+            max_ids: config.max_ids,
+        }
+    }
+
+    /// The buffered value of an ID and when it last changed, if it has
+    /// increments that haven't been written out yet.
+    fn value(&self, kind: Kind, id: &str) -> Option<(i64, DateTime<Utc>)> {
+        let pending = self.pending.lock().unwrap();
+        pending
+            .get(&(kind, id.to_owned()))
+            .filter(|p| p.flushing != 0 || p.delta != 0)
+            .map(|p| (p.value(), p.updated_at))
+    }
+
+    fn forget(&self, kind: Kind, id: &str) {
+        self.pending.lock().unwrap().remove(&(kind, id.to_owned()));
+    }
+
+    /// Writes out all buffered increments. IDs that saw no increments since
+    /// the last flush are dropped from the buffer. The caller holds
+    /// `flushing`.
+    async fn write_out(&self) -> Result<()> {
+        let ops: Vec<_> = {
+            let mut pending = self.pending.lock().unwrap();
+            pending.retain(|_, p| p.delta != 0);
+            pending
+                .iter_mut()
+                .map(|((kind, id), p)| {
+                    p.flushing = mem::take(&mut p.delta);
+                    (*kind, id.clone(), Op::Increment(p.flushing))
+                })
+                .collect()
+        };
+        if ops.is_empty() {
+            return Ok(());
+        }
+        let result = self.inner.batch(&ops).await;
+        let mut pending = self.pending.lock().unwrap();
+        match result {
+            Ok(values) => {
+                for ((kind, id, _), value) in ops.into_iter().zip(values) {
+                    match value {
+                        Some(value) => {
+                            if let Some(p) = pending.get_mut(&(kind, id)) {
+                                p.base = value;
+                                p.flushing = 0;
+                            }
+                        }
+                        // Overflowed in the backend, e.g. because another
+                        // instance wrote to it. Re-read it on the next hit.
+                        None => {
+                            pending.remove(&(kind, id));
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Err(e) => {
+                for (kind, id, _) in ops {
+                    if let Some(p) = pending.get_mut(&(kind, id)) {
+                        p.delta += mem::take(&mut p.flushing);
+                    }
+                }
+                Err(e)
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Storage for Buffered {
+    async fn migrate(&self) -> Result<()> {
+        self.inner.migrate().await
+    }
+
+    async fn stats(&self) -> Result<Stats> {
+        self.inner.stats().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let _flushing = self.flushing.lock().await;
+        self.write_out().await
+    }
+
+    async fn create(&self, kind: Kind, id: &str, value: i64) -> Result<()> {
+        self.inner.create(kind, id, value).await
+    }
+
+    async fn get(&self, kind: Kind, id: &str) -> Result<Option<Record>> {
+        let record = self.inner.get(kind, id).await?;
+        let Some((value, updated_at)) = self.value(kind, id) else {
+            return Ok(record);
+        };
+        Ok(Some(match record {
+            Some(record) => Record {
+                value,
+                updated_at,
+                ..record
+            },
+            // Not written out yet.
+            None => Record {
+                id: id.to_owned(),
+                value,
+                created_at: updated_at,
+                updated_at,
+            },
+        }))
+    }
+
+    async fn list(
+        &self,
+        kind: Kind,
+        prefix: &str,
+        labels: &Labels,
+        after: &str,
+        limit: i64,
+    ) -> Result<Vec<Record>> {
+        self.inner.list(kind, prefix, labels, after, limit).await
+    }
+
+    async fn increment(&self, kind: Kind, id: &str, by: i64) -> Result<Option<i64>> {
+        let _bypassing = self.bypassing.read().await;
+        let key = (kind, id.to_owned());
+        if !self.pending.lock().unwrap().contains_key(&key) {
+            let base = match self.inner.get(kind, id).await? {
+                Some(record) => record.value,
+                None => 0,
+            };
+            self.pending
+                .lock()
+                .unwrap()
+                .entry(key.clone())
+                .or_insert(Pending {
+                    base,
+                    flushing: 0,
+                    delta: 0,
+                    updated_at: Utc::now(),
+                });
+        }
+        let (value, full) = {
+            let mut pending = self.pending.lock().unwrap();
+            let full = pending.len() > self.max_ids;
+            match pending.get_mut(&key) {
+                Some(p) => match p.value().checked_add(by) {
+                    Some(value) => {
+                        p.delta += by;
+                        p.updated_at = Utc::now();
+                        (Some(value), full)
+                    }
+                    None => return Ok(None),
+                },
+                // Dropped by a flush in the meantime.
+                None => (None, false),
+            }
+        };
+        let Some(value) = value else {
+            return self.inner.increment(kind, id, by).await;
+        };
+        if full {
+            self.flush().await?;
+        }
+        Ok(Some(value))
+    }
+
+    async fn batch(&self, ops: &[(Kind, String, Op)]) -> Result<Vec<Option<i64>>> {
+        let _bypassing = self.bypassing.write().await;
+        let _flushing = self.flushing.lock().await;
+        self.write_out().await?;
+        for (kind, id, _) in ops {
+            self.forget(*kind, id);
+        }
+        self.inner.batch(ops).await
+    }
+
+    async fn set(&self, kind: Kind, id: &str, value: i64) -> Result<i64> {
+        let _bypassing = self.bypassing.write().await;
+        let _flushing = self.flushing.lock().await;
+        self.write_out().await?;
+        self.forget(kind, id);
+        self.inner.set(kind, id, value).await
+    }
+
+    async fn reset(&self, kind: Kind, id: &str) -> Result<bool> {
+        let _bypassing = self.bypassing.write().await;
+        let _flushing = self.flushing.lock().await;
+        self.write_out().await?;
+        self.forget(kind, id);
+        self.inner.reset(kind, id).await
+    }
+
+    async fn delete(&self, kind: Kind, id: &str) -> Result<bool> {
+        let _bypassing = self.bypassing.write().await;
+        let _flushing = self.flushing.lock().await;
+        self.write_out().await?;
+        self.forget(kind, id);
+        self.inner.delete(kind, id).await
+    }
+
+    async fn deleted(&self, kind: Kind, id: &str) -> Result<bool> {
+        self.inner.deleted(kind, id).await
+    }
+
+    async fn total(&self) -> Result<i64> {
+        self.inner.total().await
+    }
+
+    async fn highest(&self) -> Result<i64> {
+        self.inner.highest().await
+    }
+
+    async fn labels(&self, kind: Kind, id: &str) -> Result<Labels> {
+        self.inner.labels(kind, id).await
+    }
+
+    async fn set_labels(&self, kind: Kind, id: &str, labels: &Labels) -> Result<()> {
+        self.inner.set_labels(kind, id, labels).await
+    }
+
+    async fn make_unique(&self, id: &str) -> Result<()> {
+        self.inner.make_unique(id).await
+    }
+
+    async fn unique(&self, id: &str) -> Result<bool> {
+        self.inner.unique(id).await
+    }
+
+    async fn visit(&self, id: &str, visitor: u64) -> Result<Option<i64>> {
+        self.inner.visit(id, visitor).await
+    }
+
+    async fn salt(&self, day: i64) -> Result<String> {
+        self.inner.salt(day).await
+    }
+
+    async fn owner(&self, kind: Kind, id: &str) -> Result<Option<Owner>> {
+        self.inner.owner(kind, id).await
+    }
+
+    async fn claim(&self, kind: Kind, id: &str, token_hash: &str, protected: bool) -> Result<()> {
+        self.inner.claim(kind, id, token_hash, protected).await
+    }
+
+    async fn set_protected(&self, kind: Kind, id: &str, protected: bool) -> Result<()> {
+        self.inner.set_protected(kind, id, protected).await
+    }
+
+    async fn history(
+        &self,
+        kind: Kind,
+        id: &str,
+        step: Step,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<Bucket>> {
+        self.inner.history(kind, id, step, from, to).await
+    }
+
+    async fn prune_history(&self) -> Result<()> {
+        self.inner.prune_history().await
+    }
+
+    async fn remember(
+        &self,
+        key: &str,
+        request: &str,
+        since: i64,
+        abandoned: i64,
+    ) -> Result<Option<Remembered>> {
+        self.inner.remember(key, request, since, abandoned).await
+    }
+
+    async fn respond(&self, key: &str, response: &Response) -> Result<()> {
+        self.inner.respond(key, response).await
+    }
+
+    async fn forget(&self, key: &str) -> Result<()> {
+        self.inner.forget(key).await
+    }
+
+    async fn prune_idempotency_keys(&self, before: i64) -> Result<()> {
+        self.inner.prune_idempotency_keys(before).await
+    }
+
+    async fn blocks(&self) -> Result<Vec<Block>> {
+        self.inner.blocks().await
+    }
+
+    async fn block(&self, pattern: &str, prefix: bool) -> Result<()> {
+        self.inner.block(pattern, prefix).await
+    }
+
+    async fn unblock(&self, pattern: &str) -> Result<bool> {
+        self.inner.unblock(pattern).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures_util::join;
+
+    async fn buffered(name: &str) -> (Arc<dyn Storage>, Buffered) {
+        let path = env::temp_dir().join(format!(
+            "tickrs-buffered-{}-{}.db",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let inner = crate::storage::connect(&format!("sqlite://{}", path.display()))
+            .await
+            .unwrap();
+        inner.migrate().await.unwrap();
+        let config = Config {
+            interval: Duration::from_secs(60),
+            max_ids: DEFAULT_MAX_IDS,
+        };
+        (inner.clone(), Buffered::new(inner, &config))
+    }
+
+    async fn stored(inner: &Arc<dyn Storage>, kind: Kind, id: &str) -> Option<i64> {
+        inner
+            .get(kind, id)
+            .await
+            .unwrap()
+            .map(|record| record.value)
+    }
+
+    #[actix_web::test]
+    async fn flush_drains_the_buffer() {
+        let (inner, buffered) = buffered("flush").await;
+        for _ in 0..3 {
+            buffered.increment(Kind::Counter, "a", 2).await.unwrap();
+        }
+        assert_eq!(stored(&inner, Kind::Counter, "a").await, None);
+        assert_eq!(
+            buffered
+                .get(Kind::Counter, "a")
+                .await
+                .unwrap()
+                .unwrap()
+                .value,
+            6
+        );
+
+        buffered.flush().await.unwrap();
+        assert_eq!(stored(&inner, Kind::Counter, "a").await, Some(6));
+        assert!(buffered.value(Kind::Counter, "a").is_none());
+    }
+
+    #[actix_web::test]
+    async fn reads_keep_the_time_of_the_last_increment() {
+        let (_, buffered) = buffered("updated-at").await;
+        buffered.increment(Kind::Counter, "a", 1).await.unwrap();
+        let first = buffered.get(Kind::Counter, "a").await.unwrap().unwrap();
+        let second = buffered.get(Kind::Counter, "a").await.unwrap().unwrap();
+        assert_eq!(first.updated_at, second.updated_at);
+    }
+
+    #[actix_web::test]
+    async fn drops_entries_that_overflow_in_the_backend() {
+        let (inner, buffered) = buffered("overflow").await;
+        inner.set(Kind::Gauge, "g", i64::MAX - 1).await.unwrap();
+        assert_eq!(
+            buffered.increment(Kind::Gauge, "g", 1).await.unwrap(),
+            Some(i64::MAX)
+        );
+        // Another instance gets there first.
+        inner.set(Kind::Gauge, "g", i64::MAX).await.unwrap();
+
+        buffered.flush().await.unwrap();
+        assert!(!buffered
+            .pending
+            .lock()
+            .unwrap()
+            .contains_key(&(Kind::Gauge, "g".to_owned())));
+        assert_eq!(
+            buffered.get(Kind::Gauge, "g").await.unwrap().unwrap().value,
+            i64::MAX
+        );
+    }
+
+    #[actix_web::test]
+    async fn keeps_increments_made_during_a_batch() {
+        let (inner, buffered) = buffered("batch").await;
+        buffered.increment(Kind::Counter, "a", 1).await.unwrap();
+        let ops = [(Kind::Counter, "a".to_owned(), Op::Get)];
+        let (read, incremented) = join!(
+            buffered.batch(&ops),
+            buffered.increment(Kind::Counter, "a", 1)
+        );
+        assert_eq!(read.unwrap(), [Some(1)]);
+        assert_eq!(incremented.unwrap(), Some(2));
+
+        buffered.flush().await.unwrap();
+        assert_eq!(stored(&inner, Kind::Counter, "a").await, Some(2));
+    }
+
+    #[actix_web::test]
+    async fn keeps_increments_made_during_a_set() {
+        let (inner, buffered) = buffered("set").await;
+        buffered.increment(Kind::Gauge, "g", 1).await.unwrap();
+        let (set, incremented) = join!(
+            buffered.set(Kind::Gauge, "g", 10),
+            buffered.increment(Kind::Gauge, "g", 1)
+        );
+        assert_eq!(set.unwrap(), 10);
+        assert_eq!(incremented.unwrap(), Some(11));
+
+        buffered.flush().await.unwrap();
+        assert_eq!(stored(&inner, Kind::Gauge, "g").await, Some(11));
+    }
+}
@@ -0,0 +1,167 @@
+use crate::{
+    blocklist::Block,
+    history::{Bucket, Step},
+    idempotency::{Remembered, Response},
+    labels::Labels,
+    token::Owner,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::Arc;
+
+pub mod buffered;
+pub mod notifying;
+#[cfg(feature = "postgres")]
+mod postgres;
+mod sqlite;
+
+/// Which table a counter-like lives in. Counters and gauges with the same ID
+/// are separate records.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+/// One step of a `Storage::batch`.
+#[derive(Clone, Copy)]
+pub enum Op {
+    Get,
+    Increment(i64),
+    Set(i64),
+}
+
+#[derive(Serialize)]
+pub struct Record {
+    pub id: String,
+    pub value: i64,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// Figures for the `tickrs_db_*` metrics. Backends fill in what they can.
+#[derive(Default)]
+pub struct Stats {
+    pub counters: i64,
+    pub gauges: i64,
+    pub size_bytes: Option<i64>,
+    pub wal_size_bytes: Option<i64>,
+    pub page_count: Option<i64>,
+    pub freelist_count: Option<i64>,
+}
+
+#[async_trait]
+pub trait Storage: Send + Sync {
+    async fn migrate(&self) -> Result<()>;
+    async fn stats(&self) -> Result<Stats>;
+    /// Writes out anything held in memory. Called periodically and on
+    /// shutdown.
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    async fn create(&self, kind: Kind, id: &str, value: i64) -> Result<()>;
+    async fn get(&self, kind: Kind, id: &str) -> Result<Option<Record>>;
+    /// Up to `limit` records starting with `prefix` and having all of
+    /// `labels`, ordered by the bytes of their IDs, after the ID `after`.
+    async fn list(
+        &self,
+        kind: Kind,
+        prefix: &str,
+        labels: &Labels,
+        after: &str,
+        limit: i64,
+    ) -> Result<Vec<Record>>;
+    /// Adds `by` to the value, creating the record if needed, and returns the
+    /// new value. Returns `None` if the value would overflow.
+    async fn increment(&self, kind: Kind, id: &str, by: i64) -> Result<Option<i64>>;
+    /// Runs `ops` in one transaction, returning the resulting values in the
+    /// same order. A value is `None` if the record doesn't exist (for
+    /// `Op::Get`) or would overflow (for `Op::Increment`).
+    async fn batch(&self, ops: &[(Kind, String, Op)]) -> Result<Vec<Option<i64>>>;
+    /// Sets the value, creating the record if needed. Only gauges can be set.
+    async fn set(&self, kind: Kind, id: &str, value: i64) -> Result<i64>;
+    async fn reset(&self, kind: Kind, id: &str) -> Result<bool>;
+    /// Removes the record, its history, owner, labels and sketch, leaving a
+    /// tombstone behind.
+    async fn delete(&self, kind: Kind, id: &str) -> Result<bool>;
+    async fn deleted(&self, kind: Kind, id: &str) -> Result<bool>;
+    async fn total(&self) -> Result<i64>;
+    async fn highest(&self) -> Result<i64>;
+
+    async fn labels(&self, kind: Kind, id: &str) -> Result<Labels>;
+    /// Replaces all of the record's labels.
+    async fn set_labels(&self, kind: Kind, id: &str, labels: &Labels) -> Result<()>;
+
+    /// Makes a counter count unique visitors rather than hits.
+    async fn make_unique(&self, id: &str) -> Result<()>;
+    async fn unique(&self, id: &str) -> Result<bool>;
+    /// Adds a visitor to a unique counter's sketch, setting the counter to
+    /// the new estimate, which is returned. Returns `None` if the counter
+    /// isn't unique.
+    async fn visit(&self, id: &str, visitor: u64) -> Result<Option<i64>>;
+    /// The salt for hashing visitors on `day`, made up on first use. Salts
+    /// for earlier days are deleted.
+    async fn salt(&self, day: i64) -> Result<String>;
+
+    async fn owner(&self, kind: Kind, id: &str) -> Result<Option<Owner>>;
+    async fn claim(&self, kind: Kind, id: &str, token_hash: &str, protected: bool) -> Result<()>;
+    async fn set_protected(&self, kind: Kind, id: &str, protected: bool) -> Result<()>;
+
+    async fn history(
+        &self,
+        kind: Kind,
+        id: &str,
+        step: Step,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<Bucket>>;
+    async fn prune_history(&self) -> Result<()>;
+
+    /// Claims an idempotency key for `request`, unless it was claimed at or
+    /// after `since`, in which case what's remembered about it is returned.
+    /// A claim still waiting for its response is given up on if it was made
+    /// before `abandoned`.
+    async fn remember(
+        &self,
+        key: &str,
+        request: &str,
+        since: i64,
+        abandoned: i64,
+    ) -> Result<Option<Remembered>>;
+    /// Stores the response to a request with a claimed idempotency key.
+    async fn respond(&self, key: &str, response: &Response) -> Result<()>;
+    async fn forget(&self, key: &str) -> Result<()>;
+    /// Deletes idempotency keys claimed before `before`.
+    async fn prune_idempotency_keys(&self, before: i64) -> Result<()>;
+
+    async fn blocks(&self) -> Result<Vec<Block>>;
+    async fn block(&self, pattern: &str, prefix: bool) -> Result<()>;
+    async fn unblock(&self, pattern: &str) -> Result<bool>;
+}
+
+/// Upper bound on buckets returned by a single history query.
+const MAX_BUCKETS: i64 = 10_000;
+
+/// Per-minute buckets older than this many seconds are pruned.
+const MINUTE_RETENTION: i64 = 2 * 24 * 60 * 60;
+
+/// Per-hour buckets older than this many seconds are pruned. Per-day buckets
+/// are kept forever.
+const HOUR_RETENTION: i64 = 90 * 24 * 60 * 60;
+
+/// Connects to the backend picked by the scheme of `url`.
+pub async fn connect(url: &str) -> Result<Arc<dyn Storage>> {
+    if url.starts_with("sqlite:") {
+        return Ok(Arc::new(sqlite::SqliteStorage::connect(url).await?));
+    }
+    if url.starts_with("postgres:") || url.starts_with("postgresql:") {
+        #[cfg(feature = "postgres")]
+        return Ok(Arc::new(postgres::PostgresStorage::connect(url).await?));
+        #[cfg(not(feature = "postgres"))]
+        bail!("PostgreSQL support requires building with the `postgres` feature");
+    }
+    bail!("Unsupported DATABASE_URL scheme")
+}
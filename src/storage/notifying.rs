@@ -0,0 +1,200 @@
+use super::{Kind, Op, Record, Stats, Storage};
+use crate::{
+    blocklist::Block,
+    events::Events,
+    history::{Bucket, Step},
+    idempotency::{Remembered, Response},
+    labels::Labels,
+    token::Owner,
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use std::sync::Arc;
+
+/// Publishes every new value written through `inner` to `events`, and every
+/// deletion.
+pub struct Notifying {
+    inner: Arc<dyn Storage>,
+    events: Arc<Events>,
+}
+
+impl Notifying {
+    pub fn new(inner: Arc<dyn Storage>, events: Arc<Events>) -> Self {
+        Self { inner, events }
+    }
+}
+
+#[async_trait]
+impl Storage for Notifying {
+    async fn migrate(&self) -> Result<()> {
+        self.inner.migrate().await
+    }
+
+    async fn stats(&self) -> Result<Stats> {
+        self.inner.stats().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn create(&self, kind: Kind, id: &str, value: i64) -> Result<()> {
+        self.inner.create(kind, id, value).await
+    }
+
+    async fn get(&self, kind: Kind, id: &str) -> Result<Option<Record>> {
+        self.inner.get(kind, id).await
+    }
+
+    async fn list(
+        &self,
+        kind: Kind,
+        prefix: &str,
+        labels: &Labels,
+        after: &str,
+        limit: i64,
+    ) -> Result<Vec<Record>> {
+        self.inner.list(kind, prefix, labels, after, limit).await
+    }
+
+    async fn increment(&self, kind: Kind, id: &str, by: i64) -> Result<Option<i64>> {
+        let value = self.inner.increment(kind, id, by).await?;
+        if let Some(value) = value {
+            self.events.publish(kind, id, value);
+        }
+        Ok(value)
+    }
+
+    async fn batch(&self, ops: &[(Kind, String, Op)]) -> Result<Vec<Option<i64>>> {
+        let values = self.inner.batch(ops).await?;
+        for ((kind, id, op), value) in ops.iter().zip(&values) {
+            if let (Op::Increment(_) | Op::Set(_), Some(value)) = (op, value) {
+                self.events.publish(*kind, id, *value);
+            }
+        }
+        Ok(values)
+    }
+
+    async fn set(&self, kind: Kind, id: &str, value: i64) -> Result<i64> {
+        let value = self.inner.set(kind, id, value).await?;
+        self.events.publish(kind, id, value);
+        Ok(value)
+    }
+
+    async fn reset(&self, kind: Kind, id: &str) -> Result<bool> {
+        let reset = self.inner.reset(kind, id).await?;
+        if reset {
+            self.events.publish(kind, id, 0);
+        }
+        Ok(reset)
+    }
+
+    async fn delete(&self, kind: Kind, id: &str) -> Result<bool> {
+        let deleted = self.inner.delete(kind, id).await?;
+        if deleted {
+            self.events.publish_deletion(kind, id);
+        }
+        Ok(deleted)
+    }
+
+    async fn deleted(&self, kind: Kind, id: &str) -> Result<bool> {
+        self.inner.deleted(kind, id).await
+    }
+
+    async fn total(&self) -> Result<i64> {
+        self.inner.total().await
+    }
+
+    async fn highest(&self) -> Result<i64> {
+        self.inner.highest().await
+    }
+
+    async fn labels(&self, kind: Kind, id: &str) -> Result<Labels> {
+        self.inner.labels(kind, id).await
+    }
+
+    async fn set_labels(&self, kind: Kind, id: &str, labels: &Labels) -> Result<()> {
+        self.inner.set_labels(kind, id, labels).await
+    }
+
+    async fn make_unique(&self, id: &str) -> Result<()> {
+        self.inner.make_unique(id).await
+    }
+
+    async fn unique(&self, id: &str) -> Result<bool> {
+        self.inner.unique(id).await
+    }
+
+    async fn visit(&self, id: &str, visitor: u64) -> Result<Option<i64>> {
+        let value = self.inner.visit(id, visitor).await?;
+        if let Some(value) = value {
+            self.events.publish(Kind::Counter, id, value);
+        }
+        Ok(value)
+    }
+
+    async fn salt(&self, day: i64) -> Result<String> {
+        self.inner.salt(day).await
+    }
+
+    async fn owner(&self, kind: Kind, id: &str) -> Result<Option<Owner>> {
+        self.inner.owner(kind, id).await
+    }
+
+    async fn claim(&self, kind: Kind, id: &str, token_hash: &str, protected: bool) -> Result<()> {
+        self.inner.claim(kind, id, token_hash, protected).await
+    }
+
+    async fn set_protected(&self, kind: Kind, id: &str, protected: bool) -> Result<()> {
+        self.inner.set_protected(kind, id, protected).await
+    }
+
+    async fn history(
+        &self,
+        kind: Kind,
+        id: &str,
+        step: Step,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<Bucket>> {
+        self.inner.history(kind, id, step, from, to).await
+    }
+
+    async fn prune_history(&self) -> Result<()> {
+        self.inner.prune_history().await
+    }
+
+    async fn remember(
+        &self,
+        key: &str,
+        request: &str,
+        since: i64,
+        abandoned: i64,
+    ) -> Result<Option<Remembered>> {
+        self.inner.remember(key, request, since, abandoned).await
+    }
+
+    async fn respond(&self, key: &str, response: &Response) -> Result<()> {
+        self.inner.respond(key, response).await
+    }
+
+    async fn forget(&self, key: &str) -> Result<()> {
+        self.inner.forget(key).await
+    }
+
+    async fn prune_idempotency_keys(&self, before: i64) -> Result<()> {
+        self.inner.prune_idempotency_keys(before).await
+    }
+
+    async fn blocks(&self) -> Result<Vec<Block>> {
+        self.inner.blocks().await
+    }
+
+    async fn block(&self, pattern: &str, prefix: bool) -> Result<()> {
+        self.inner.block(pattern, prefix).await
+    }
+
+    async fn unblock(&self, pattern: &str) -> Result<bool> {
+        self.inner.unblock(pattern).await
+    }
+}
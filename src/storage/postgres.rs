@@ -0,0 +1,670 @@
+use super::{Kind, Op, Record, Stats, Storage, HOUR_RETENTION, MAX_BUCKETS, MINUTE_RETENTION};
+use crate::{
+    blocklist::Block,
+    history::{Bucket, Step},
+    hll::Sketch,
+    idempotency::{Remembered, Response},
+    labels::Labels,
+    token::Owner,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use nanoid::nanoid;
+use sqlx::{postgres::PgPool, PgConnection};
+
+pub struct PostgresStorage {
+    pool: PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn connect(url: &str) -> Result<Self> {
+        Ok(Self {
+            pool: PgPool::connect(url).await?,
+        })
+    }
+}
+
+fn table(kind: Kind) -> &'static str {
+    match kind {
+        Kind::Counter => "c",
+        Kind::Gauge => "g",
+    }
+}
+
+/// A condition on rows of `table` having every label in the JSON object
+/// bound at `param`.
+fn has_labels(table: &str, param: usize) -> String {
+    format!(
+        r#"NOT EXISTS (
+               SELECT 1 FROM jsonb_each_text(${param}::jsonb) AS s WHERE NOT EXISTS (
+                   SELECT 1 FROM {table}_label AS l
+                   WHERE l.nano_id = {table}.nano_id AND l.key = s.key AND l.value = s.value
+               )
+           )"#
+    )
+}
+
+type RecordRow = (String, i64, DateTime<Utc>, DateTime<Utc>);
+
+fn record((id, value, created_at, updated_at): RecordRow) -> Record {
+    Record {
+        id,
+        value,
+        created_at,
+        updated_at,
+    }
+}
+
+/// Adds `delta` to the current minute, hour and day buckets, recording
+/// `value` as the latest value seen in each.
+async fn record_history(
+    conn: &mut PgConnection,
+    kind: Kind,
+    id: &str,
+    delta: i64,
+    value: i64,
+) -> Result<()> {
+    let table = table(kind);
+    sqlx::query(&format!(
+        r#"INSERT INTO {table}_history (nano_id, step, bucket, delta, value)
+           SELECT $1, s, $2 - $2 % s, $3, $4
+           FROM (VALUES (60), (3600), (86400)) AS steps(s)
+           ON CONFLICT (nano_id, step, bucket) DO UPDATE SET
+             delta = {table}_history.delta + excluded.delta,
+             value = excluded.value"#
+    ))
+    .bind(id)
+    .bind(Utc::now().timestamp())
+    .bind(delta)
+    .bind(value)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Records a gauge being set to `value`. Must run before the gauge itself is
+/// updated, as the delta is taken from the gauge's current value.
+async fn record_gauge_set(conn: &mut PgConnection, id: &str, value: i64) -> Result<()> {
+    sqlx::query(
+        r#"INSERT INTO g_history (nano_id, step, bucket, delta, value)
+           SELECT $1, s, $2 - $2 % s,
+             $3 - COALESCE((SELECT value FROM g WHERE nano_id = $1), 0), $3
+           FROM (VALUES (60), (3600), (86400)) AS steps(s)
+           ON CONFLICT (nano_id, step, bucket) DO UPDATE SET
+             delta = g_history.delta + excluded.delta,
+             value = excluded.value"#,
+    )
+    .bind(id)
+    .bind(Utc::now().timestamp())
+    .bind(value)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Adds `by` to the value, creating the record if needed, and records it in
+/// the history. Returns `None` if the value would overflow.
+async fn increment(conn: &mut PgConnection, kind: Kind, id: &str, by: i64) -> Result<Option<i64>> {
+    let table = table(kind);
+    let value: Option<i64> = sqlx::query_scalar(&format!(
+        r#"INSERT INTO {table} (nano_id, value) VALUES ($1, $2)
+           ON CONFLICT (nano_id) DO UPDATE SET
+             value = {table}.value + $2,
+             updated_at = now()
+           WHERE {table}.value::numeric + $2
+             BETWEEN -9223372036854775808 AND 9223372036854775807
+           RETURNING value"#
+    ))
+    .bind(id)
+    .bind(by)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    record_history(conn, kind, id, by, value).await?;
+    Ok(Some(value))
+}
+
+/// Sets a gauge to `value`, creating it if needed, and records it in the
+/// history.
+async fn set(conn: &mut PgConnection, id: &str, value: i64) -> Result<i64> {
+    record_gauge_set(conn, id, value).await?;
+    Ok(sqlx::query_scalar(
+        r#"INSERT INTO g (nano_id, value) VALUES ($1, $2)
+           ON CONFLICT (nano_id) DO UPDATE SET
+             value = excluded.value,
+             updated_at = now()
+           RETURNING value"#,
+    )
+    .bind(id)
+    .bind(value)
+    .fetch_one(&mut *conn)
+    .await?)
+}
+
+async fn value(conn: &mut PgConnection, kind: Kind, id: &str) -> Result<Option<i64>> {
+    Ok(sqlx::query_scalar(&format!(
+        "SELECT value FROM {} WHERE nano_id = $1",
+        table(kind)
+    ))
+    .bind(id)
+    .fetch_optional(&mut *conn)
+    .await?)
+}
+
+#[async_trait]
+impl Storage for PostgresStorage {
+    async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("migrations/postgres")
+            .run(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<Stats> {
+        let counters = sqlx::query_scalar("SELECT COUNT(*) FROM c")
+            .fetch_one(&self.pool)
+            .await?;
+        let gauges = sqlx::query_scalar("SELECT COUNT(*) FROM g")
+            .fetch_one(&self.pool)
+            .await?;
+        let size_bytes = sqlx::query_scalar("SELECT pg_database_size(current_database())")
+            .fetch_one(&self.pool)
+            .await
+            .ok();
+        Ok(Stats {
+            counters,
+            gauges,
+            size_bytes,
+            ..Stats::default()
+        })
+    }
+
+    async fn create(&self, kind: Kind, id: &str, value: i64) -> Result<()> {
+        sqlx::query(&format!(
+            "INSERT INTO {} ( nano_id, value ) VALUES ( $1, $2 )",
+            table(kind)
+        ))
+        .bind(id)
+        .bind(value)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn get(&self, kind: Kind, id: &str) -> Result<Option<Record>> {
+        Ok(sqlx::query_as::<_, RecordRow>(&format!(
+            "SELECT nano_id, value, created_at, updated_at FROM {} WHERE nano_id = $1",
+            table(kind)
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(record))
+    }
+
+    async fn list(
+        &self,
+        kind: Kind,
+        prefix: &str,
+        labels: &Labels,
+        after: &str,
+        limit: i64,
+    ) -> Result<Vec<Record>> {
+        let table = table(kind);
+        Ok(sqlx::query_as::<_, RecordRow>(&format!(
+            r#"SELECT nano_id, value, created_at, updated_at FROM {table}
+               WHERE left(nano_id, length($1)) = $1 AND nano_id > $2 COLLATE "C"
+               AND {}
+               ORDER BY nano_id COLLATE "C" LIMIT $3"#,
+            has_labels(table, 4)
+        ))
+        .bind(prefix)
+        .bind(after)
+        .bind(limit)
+        .bind(serde_json::to_string(labels)?)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(record)
+        .collect())
+    }
+
+    async fn increment(&self, kind: Kind, id: &str, by: i64) -> Result<Option<i64>> {
+        let mut tx = self.pool.begin().await?;
+        let value = increment(&mut tx, kind, id, by).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    async fn batch(&self, ops: &[(Kind, String, Op)]) -> Result<Vec<Option<i64>>> {
+        let mut tx = self.pool.begin().await?;
+        let mut values = Vec::with_capacity(ops.len());
+        for (kind, id, op) in ops {
+            values.push(match *op {
+                Op::Get => value(&mut tx, *kind, id).await?,
+                Op::Increment(by) => increment(&mut tx, *kind, id, by).await?,
+                Op::Set(value) if *kind == Kind::Gauge => Some(set(&mut tx, id, value).await?),
+                Op::Set(_) => bail!("only gauges can be set"),
+            });
+        }
+        tx.commit().await?;
+        Ok(values)
+    }
+
+    async fn set(&self, kind: Kind, id: &str, value: i64) -> Result<i64> {
+        if kind != Kind::Gauge {
+            bail!("only gauges can be set");
+        }
+        let mut tx = self.pool.begin().await?;
+        let value = set(&mut tx, id, value).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    async fn reset(&self, kind: Kind, id: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        match kind {
+            Kind::Counter => {
+                record_history(&mut tx, kind, id, 0, 0).await?;
+                sqlx::query("UPDATE c_sketch SET sketch = $2 WHERE nano_id = $1")
+                    .bind(id)
+                    .bind(Sketch::new().as_bytes())
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            Kind::Gauge => record_gauge_set(&mut tx, id, 0).await?,
+        }
+        let reset = sqlx::query(&format!(
+            "UPDATE {} SET value = 0, updated_at = now() WHERE nano_id = $1",
+            table(kind)
+        ))
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            > 0;
+        if reset {
+            tx.commit().await?;
+        }
+        Ok(reset)
+    }
+
+    async fn delete(&self, kind: Kind, id: &str) -> Result<bool> {
+        let table = table(kind);
+        let mut tx = self.pool.begin().await?;
+        let deleted = sqlx::query(&format!("DELETE FROM {table} WHERE nano_id = $1"))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected()
+            > 0;
+        if deleted {
+            sqlx::query(&format!("DELETE FROM {table}_history WHERE nano_id = $1"))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&format!("DELETE FROM {table}_owner WHERE nano_id = $1"))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            sqlx::query(&format!("DELETE FROM {table}_label WHERE nano_id = $1"))
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+            if kind == Kind::Counter {
+                sqlx::query("DELETE FROM c_sketch WHERE nano_id = $1")
+                    .bind(id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            sqlx::query(&format!(
+                r#"INSERT INTO {table}_deleted ( nano_id ) VALUES ( $1 )
+                   ON CONFLICT (nano_id) DO UPDATE SET deleted_at = now()"#
+            ))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn deleted(&self, kind: Kind, id: &str) -> Result<bool> {
+        Ok(sqlx::query(&format!(
+            "SELECT nano_id FROM {}_deleted WHERE nano_id = $1",
+            table(kind)
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .is_some())
+    }
+
+    async fn total(&self) -> Result<i64> {
+        Ok(
+            sqlx::query_scalar("SELECT (SELECT count(id) FROM c) + (SELECT count(id) FROM g)")
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    async fn highest(&self) -> Result<i64> {
+        Ok(sqlx::query_scalar(
+            "SELECT value FROM c UNION SELECT value FROM g ORDER BY value DESC LIMIT 1",
+        )
+        .fetch_one(&self.pool)
+        .await?)
+    }
+
+    async fn labels(&self, kind: Kind, id: &str) -> Result<Labels> {
+        Ok(sqlx::query_as::<_, (String, String)>(&format!(
+            "SELECT key, value FROM {}_label WHERE nano_id = $1",
+            table(kind)
+        ))
+        .bind(id)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect())
+    }
+
+    async fn set_labels(&self, kind: Kind, id: &str, labels: &Labels) -> Result<()> {
+        let table = table(kind);
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(&format!("DELETE FROM {table}_label WHERE nano_id = $1"))
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        for (key, value) in labels {
+            sqlx::query(&format!(
+                "INSERT INTO {table}_label ( nano_id, key, value ) VALUES ( $1, $2, $3 )"
+            ))
+            .bind(id)
+            .bind(key)
+            .bind(value)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn make_unique(&self, id: &str) -> Result<()> {
+        sqlx::query("INSERT INTO c_sketch ( nano_id, sketch ) VALUES ( $1, $2 )")
+            .bind(id)
+            .bind(Sketch::new().as_bytes())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn unique(&self, id: &str) -> Result<bool> {
+        Ok(
+            sqlx::query("SELECT nano_id FROM c_sketch WHERE nano_id = $1")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?
+                .is_some(),
+        )
+    }
+
+    async fn visit(&self, id: &str, visitor: u64) -> Result<Option<i64>> {
+        let mut tx = self.pool.begin().await?;
+        let Some(bytes) = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT sketch FROM c_sketch WHERE nano_id = $1 FOR UPDATE",
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?
+        else {
+            return Ok(None);
+        };
+        let mut sketch = Sketch::from_bytes(bytes);
+        sketch.insert(visitor);
+        sqlx::query("UPDATE c_sketch SET sketch = $2 WHERE nano_id = $1")
+            .bind(id)
+            .bind(sketch.as_bytes())
+            .execute(&mut *tx)
+            .await?;
+        // Estimates can dip slightly as the sketch fills up, counters can't.
+        let current = value(&mut tx, Kind::Counter, id).await?.unwrap_or(0);
+        let by = (sketch.estimate() - current).max(0);
+        let value = increment(&mut tx, Kind::Counter, id, by).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    async fn salt(&self, day: i64) -> Result<String> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO salts ( day, salt ) VALUES ( $1, $2 ) ON CONFLICT (day) DO NOTHING",
+        )
+        .bind(day)
+        .bind(nanoid!(32, &nanoid::alphabet::SAFE))
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM salts WHERE day < $1")
+            .bind(day)
+            .execute(&mut *tx)
+            .await?;
+        let salt = sqlx::query_scalar("SELECT salt FROM salts WHERE day = $1")
+            .bind(day)
+            .fetch_one(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(salt)
+    }
+
+    async fn owner(&self, kind: Kind, id: &str) -> Result<Option<Owner>> {
+        Ok(sqlx::query_as::<_, (String, bool)>(&format!(
+            "SELECT token_hash, protected FROM {}_owner WHERE nano_id = $1",
+            table(kind)
+        ))
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?
+        .map(|(token_hash, protected)| Owner {
+            token_hash,
+            protected,
+        }))
+    }
+
+    async fn claim(&self, kind: Kind, id: &str, token_hash: &str, protected: bool) -> Result<()> {
+        sqlx::query(&format!(
+            "INSERT INTO {}_owner ( nano_id, token_hash, protected ) VALUES ( $1, $2, $3 )",
+            table(kind)
+        ))
+        .bind(id)
+        .bind(token_hash)
+        .bind(protected)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_protected(&self, kind: Kind, id: &str, protected: bool) -> Result<()> {
+        sqlx::query(&format!(
+            "UPDATE {}_owner SET protected = $2 WHERE nano_id = $1",
+            table(kind)
+        ))
+        .bind(id)
+        .bind(protected)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn history(
+        &self,
+        kind: Kind,
+        id: &str,
+        step: Step,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<Bucket>> {
+        let step = step.seconds();
+        let from = from - from % step;
+        Ok(sqlx::query_as::<_, (i64, i64, i64)>(&format!(
+            r#"SELECT bucket, delta, value FROM {}_history
+               WHERE nano_id = $1 AND step = $2 AND bucket BETWEEN $3 AND $4
+               ORDER BY bucket LIMIT $5"#,
+            table(kind)
+        ))
+        .bind(id)
+        .bind(step as i32)
+        .bind(from)
+        .bind(to)
+        .bind(MAX_BUCKETS)
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|(bucket, delta, value)| Bucket::new(bucket, delta, value))
+        .collect())
+    }
+
+    async fn prune_history(&self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        for table in ["c_history", "g_history"] {
+            sqlx::query(&format!(
+                r#"DELETE FROM {table}
+                   WHERE (step = 60 AND bucket < $1) OR (step = 3600 AND bucket < $2)"#
+            ))
+            .bind(now - MINUTE_RETENTION)
+            .bind(now - HOUR_RETENTION)
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn remember(
+        &self,
+        key: &str,
+        request: &str,
+        since: i64,
+        abandoned: i64,
+    ) -> Result<Option<Remembered>> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            r#"DELETE FROM idempotency_keys
+               WHERE key = $1 AND ( created_at < $2 OR ( status IS NULL AND created_at < $3 ) )"#,
+        )
+        .bind(key)
+        .bind(since)
+        .bind(abandoned)
+        .execute(&mut *tx)
+        .await?;
+        let claimed = sqlx::query(
+            r#"INSERT INTO idempotency_keys ( key, request, created_at ) VALUES ( $1, $2, $3 )
+               ON CONFLICT (key) DO NOTHING"#,
+        )
+        .bind(key)
+        .bind(request)
+        .bind(Utc::now().timestamp())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+        let remembered = if claimed {
+            None
+        } else {
+            let (request, status, location, content_type, body) = sqlx::query_as::<
+                _,
+                (
+                    String,
+                    Option<i32>,
+                    Option<String>,
+                    Option<String>,
+                    Option<Vec<u8>>,
+                ),
+            >(
+                r#"SELECT request, status, location, content_type, body
+                   FROM idempotency_keys WHERE key = $1"#,
+            )
+            .bind(key)
+            .fetch_one(&mut *tx)
+            .await?;
+            Some(Remembered {
+                request,
+                response: status.map(|status| Response {
+                    status: status as u16,
+                    location,
+                    content_type,
+                    body: body.unwrap_or_default(),
+                }),
+            })
+        };
+        tx.commit().await?;
+        Ok(remembered)
+    }
+
+    async fn respond(&self, key: &str, response: &Response) -> Result<()> {
+        sqlx::query(
+            r#"UPDATE idempotency_keys SET status = $2, location = $3, content_type = $4, body = $5
+               WHERE key = $1"#,
+        )
+        .bind(key)
+        .bind(i32::from(response.status))
+        .bind(&response.location)
+        .bind(&response.content_type)
+        .bind(&response.body)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn forget(&self, key: &str) -> Result<()> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE key = $1")
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn prune_idempotency_keys(&self, before: i64) -> Result<()> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE created_at < $1")
+            .bind(before)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn blocks(&self) -> Result<Vec<Block>> {
+        Ok(sqlx::query_as::<_, (String, bool, DateTime<Utc>)>(
+            "SELECT pattern, prefix, created_at FROM blocked ORDER BY pattern",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|(pattern, prefix, created_at)| Block {
+            pattern,
+            prefix,
+            created_at,
+        })
+        .collect())
+    }
+
+    async fn block(&self, pattern: &str, prefix: bool) -> Result<()> {
+        sqlx::query(
+            r#"INSERT INTO blocked ( pattern, prefix ) VALUES ( $1, $2 )
+               ON CONFLICT (pattern) DO UPDATE SET prefix = excluded.prefix"#,
+        )
+        .bind(pattern)
+        .bind(prefix)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn unblock(&self, pattern: &str) -> Result<bool> {
+        Ok(sqlx::query("DELETE FROM blocked WHERE pattern = $1")
+            .bind(pattern)
+            .execute(&self.pool)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+}
@@ -0,0 +1,901 @@
+use super::{Kind, Op, Record, Stats, Storage, HOUR_RETENTION, MAX_BUCKETS, MINUTE_RETENTION};
+use crate::{
+    blocklist::Block,
+    history::{Bucket, Step},
+    hll::Sketch,
+    idempotency::{Remembered, Response},
+    labels::Labels,
+    token::Owner,
+};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use chrono::Utc;
+use nanoid::nanoid;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqlitePool},
+    SqliteConnection,
+};
+use std::str::FromStr;
+
+struct LabelRow {
+    key: String,
+    value: String,
+}
+
+pub struct SqliteStorage {
+    pool: SqlitePool,
+    path: String,
+}
+
+impl SqliteStorage {
+    pub async fn connect(url: &str) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        sqlx::query("PRAGMA journal_mode=WAL")
+            .execute(&pool)
+            .await?;
+        let path = url.strip_prefix("sqlite://").unwrap_or(url).to_owned();
+        Ok(Self { pool, path })
+    }
+}
+
+/// Adds `delta` to the current minute, hour and day buckets, recording
+/// `value` as the latest value seen in each.
+async fn record_history(
+    conn: &mut SqliteConnection,
+    kind: Kind,
+    id: &str,
+    delta: i64,
+    value: i64,
+) -> Result<()> {
+    let now = Utc::now().timestamp();
+    match kind {
+        Kind::Counter => {
+            sqlx::query!(
+                r#"INSERT INTO c_history (nano_id, step, bucket, delta, value)
+               SELECT ?1, column1, ?2 - ?2 % column1, ?3, ?4
+               FROM (VALUES (60), (3600), (86400))
+               WHERE true
+               ON CONFLICT(nano_id, step, bucket) DO UPDATE SET
+                 delta = delta + excluded.delta,
+                 value = excluded.value"#,
+                id,
+                now,
+                delta,
+                value
+            )
+            .execute(&mut *conn)
+            .await?
+        }
+        Kind::Gauge => {
+            sqlx::query!(
+                r#"INSERT INTO g_history (nano_id, step, bucket, delta, value)
+               SELECT ?1, column1, ?2 - ?2 % column1, ?3, ?4
+               FROM (VALUES (60), (3600), (86400))
+               WHERE true
+               ON CONFLICT(nano_id, step, bucket) DO UPDATE SET
+                 delta = delta + excluded.delta,
+                 value = excluded.value"#,
+                id,
+                now,
+                delta,
+                value
+            )
+            .execute(&mut *conn)
+            .await?
+        }
+    };
+    Ok(())
+}
+
+/// Records a gauge being set to `value`. Must run before the gauge itself is
+/// updated, as the delta is taken from the gauge's current value.
+async fn record_gauge_set(conn: &mut SqliteConnection, id: &str, value: i64) -> Result<()> {
+    let now = Utc::now().timestamp();
+    sqlx::query!(
+        r#"INSERT INTO g_history (nano_id, step, bucket, delta, value)
+           SELECT ?1, column1, ?2 - ?2 % column1,
+             ?3 - COALESCE((SELECT value FROM g WHERE nano_id = ?1), 0), ?3
+           FROM (VALUES (60), (3600), (86400))
+           WHERE true
+           ON CONFLICT(nano_id, step, bucket) DO UPDATE SET
+             delta = delta + excluded.delta,
+             value = excluded.value"#,
+        id,
+        now,
+        value
+    )
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Adds `by` to the value, creating the record if needed, and records it in
+/// the history. Returns `None` if the value would overflow.
+async fn increment(
+    conn: &mut SqliteConnection,
+    kind: Kind,
+    id: &str,
+    by: i64,
+) -> Result<Option<i64>> {
+    let value = match kind {
+        Kind::Counter => sqlx::query!(
+            r#"INSERT INTO c (nano_id, value) VALUES (?1, ?2)
+               ON CONFLICT(nano_id) DO UPDATE SET
+                 value = value + ?2,
+                 updated_at = datetime('now', 'utc')
+               WHERE value <= 9223372036854775807 - ?2
+               RETURNING value"#,
+            id,
+            by
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|rec| rec.value),
+        Kind::Gauge => sqlx::query!(
+            r#"INSERT INTO g (nano_id, value) VALUES (?1, ?2)
+               ON CONFLICT(nano_id) DO UPDATE SET
+                 value = value + ?2,
+                 updated_at = datetime('now', 'utc')
+               WHERE (?2 >= 0 AND value <= 9223372036854775807 - ?2)
+                  OR (?2 < 0 AND value >= -9223372036854775807 - 1 - ?2)
+               RETURNING value"#,
+            id,
+            by
+        )
+        .fetch_optional(&mut *conn)
+        .await?
+        .map(|rec| rec.value),
+    };
+    let Some(value) = value else {
+        return Ok(None);
+    };
+    record_history(conn, kind, id, by, value).await?;
+    Ok(Some(value))
+}
+
+/// Sets a gauge to `value`, creating it if needed, and records it in the
+/// history. `updated_at` is bumped by the `UPDATE_G` trigger.
+async fn set(conn: &mut SqliteConnection, id: &str, value: i64) -> Result<i64> {
+    record_gauge_set(conn, id, value).await?;
+    let rec = sqlx::query!(
+        r#"INSERT INTO g (nano_id, value) VALUES (?1, ?2)
+           ON CONFLICT(nano_id) DO UPDATE SET
+             value = excluded.value
+           RETURNING value"#,
+        id,
+        value
+    )
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(rec.value)
+}
+
+async fn value(conn: &mut SqliteConnection, kind: Kind, id: &str) -> Result<Option<i64>> {
+    Ok(match kind {
+        Kind::Counter => sqlx::query!(r#"SELECT value FROM c WHERE nano_id = ?1"#, id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|rec| rec.value),
+        Kind::Gauge => sqlx::query!(r#"SELECT value FROM g WHERE nano_id = ?1"#, id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|rec| rec.value),
+    })
+}
+
+#[async_trait]
+impl Storage for SqliteStorage {
+    async fn migrate(&self) -> Result<()> {
+        sqlx::migrate!("migrations/sqlite").run(&self.pool).await?;
+        Ok(())
+    }
+
+    async fn stats(&self) -> Result<Stats> {
+        let counters = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM c")
+            .fetch_one(&self.pool)
+            .await?;
+        let gauges = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM g")
+            .fetch_one(&self.pool)
+            .await?;
+
+        // SQLite page stats
+        let page_count = sqlx::query_scalar::<_, i64>("PRAGMA page_count")
+            .fetch_one(&self.pool)
+            .await
+            .ok();
+        let freelist_count = sqlx::query_scalar::<_, i64>("PRAGMA freelist_count")
+            .fetch_one(&self.pool)
+            .await
+            .ok();
+
+        // File sizes from the filesystem
+        let size_bytes = std::fs::metadata(&self.path)
+            .ok()
+            .map(|meta| meta.len() as i64);
+        let wal_size_bytes = Some(
+            std::fs::metadata(format!("{}-wal", self.path))
+                .map(|meta| meta.len() as i64)
+                .unwrap_or(0),
+        );
+
+        Ok(Stats {
+            counters,
+            gauges,
+            size_bytes,
+            wal_size_bytes,
+            page_count,
+            freelist_count,
+        })
+    }
+
+    async fn create(&self, kind: Kind, id: &str, value: i64) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        match kind {
+            Kind::Counter => {
+                sqlx::query!(
+                    r#"INSERT INTO c ( nano_id, value ) VALUES ( ?1, ?2 )"#,
+                    id,
+                    value
+                )
+                .execute(&mut *conn)
+                .await?
+            }
+            Kind::Gauge => {
+                sqlx::query!(
+                    r#"INSERT INTO g ( nano_id, value ) VALUES ( ?1, ?2 )"#,
+                    id,
+                    value
+                )
+                .execute(&mut *conn)
+                .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn get(&self, kind: Kind, id: &str) -> Result<Option<Record>> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(match kind {
+            Kind::Counter => sqlx::query!(
+                r#"SELECT nano_id, value, created_at, updated_at FROM c WHERE nano_id = ?1"#,
+                id
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|rec| Record {
+                id: rec.nano_id,
+                value: rec.value,
+                created_at: rec.created_at.and_utc(),
+                updated_at: rec.updated_at.and_utc(),
+            }),
+            Kind::Gauge => sqlx::query!(
+                r#"SELECT nano_id, value, created_at, updated_at FROM g WHERE nano_id = ?1"#,
+                id
+            )
+            .fetch_optional(&mut *conn)
+            .await?
+            .map(|rec| Record {
+                id: rec.nano_id,
+                value: rec.value,
+                created_at: rec.created_at.and_utc(),
+                updated_at: rec.updated_at.and_utc(),
+            }),
+        })
+    }
+
+    async fn list(
+        &self,
+        kind: Kind,
+        prefix: &str,
+        labels: &Labels,
+        after: &str,
+        limit: i64,
+    ) -> Result<Vec<Record>> {
+        let selector = serde_json::to_string(labels)?;
+        Ok(match kind {
+            Kind::Counter => sqlx::query!(
+                r#"SELECT nano_id, value, created_at, updated_at FROM c
+                   WHERE substr(nano_id, 1, length(?1)) = ?1 AND nano_id > ?2
+                   AND NOT EXISTS (
+                       SELECT 1 FROM json_each(?4) AS s WHERE NOT EXISTS (
+                           SELECT 1 FROM c_label AS l
+                           WHERE l.nano_id = c.nano_id AND l.key = s.key AND l.value = s.value
+                       )
+                   )
+                   ORDER BY nano_id LIMIT ?3"#,
+                prefix,
+                after,
+                limit,
+                selector
+            )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|rec| Record {
+                id: rec.nano_id,
+                value: rec.value,
+                created_at: rec.created_at.and_utc(),
+                updated_at: rec.updated_at.and_utc(),
+            })
+            .collect(),
+            Kind::Gauge => sqlx::query!(
+                r#"SELECT nano_id, value, created_at, updated_at FROM g
+                   WHERE substr(nano_id, 1, length(?1)) = ?1 AND nano_id > ?2
+                   AND NOT EXISTS (
+                       SELECT 1 FROM json_each(?4) AS s WHERE NOT EXISTS (
+                           SELECT 1 FROM g_label AS l
+                           WHERE l.nano_id = g.nano_id AND l.key = s.key AND l.value = s.value
+                       )
+                   )
+                   ORDER BY nano_id LIMIT ?3"#,
+                prefix,
+                after,
+                limit,
+                selector
+            )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|rec| Record {
+                id: rec.nano_id,
+                value: rec.value,
+                created_at: rec.created_at.and_utc(),
+                updated_at: rec.updated_at.and_utc(),
+            })
+            .collect(),
+        })
+    }
+
+    async fn increment(&self, kind: Kind, id: &str, by: i64) -> Result<Option<i64>> {
+        let mut tx = self.pool.begin().await?;
+        let value = increment(&mut tx, kind, id, by).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    async fn batch(&self, ops: &[(Kind, String, Op)]) -> Result<Vec<Option<i64>>> {
+        let mut tx = self.pool.begin().await?;
+        let mut values = Vec::with_capacity(ops.len());
+        for (kind, id, op) in ops {
+            values.push(match *op {
+                Op::Get => value(&mut tx, *kind, id).await?,
+                Op::Increment(by) => increment(&mut tx, *kind, id, by).await?,
+                Op::Set(value) if *kind == Kind::Gauge => Some(set(&mut tx, id, value).await?),
+                Op::Set(_) => bail!("only gauges can be set"),
+            });
+        }
+        tx.commit().await?;
+        Ok(values)
+    }
+
+    async fn set(&self, kind: Kind, id: &str, value: i64) -> Result<i64> {
+        if kind != Kind::Gauge {
+            bail!("only gauges can be set");
+        }
+        let mut tx = self.pool.begin().await?;
+        let value = set(&mut tx, id, value).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    async fn reset(&self, kind: Kind, id: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let reset = match kind {
+            Kind::Counter => {
+                record_history(&mut tx, kind, id, 0, 0).await?;
+                let empty = Sketch::new();
+                let empty = empty.as_bytes();
+                sqlx::query!(
+                    r#"UPDATE c_sketch SET sketch = ?2 WHERE nano_id = ?1"#,
+                    id,
+                    empty
+                )
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query!(r#"UPDATE c SET value = 0 WHERE nano_id = ?1"#, id)
+                    .execute(&mut *tx)
+                    .await?
+            }
+            Kind::Gauge => {
+                record_gauge_set(&mut tx, id, 0).await?;
+                sqlx::query!(r#"UPDATE g SET value = 0 WHERE nano_id = ?1"#, id)
+                    .execute(&mut *tx)
+                    .await?
+            }
+        }
+        .rows_affected()
+            > 0;
+        if reset {
+            tx.commit().await?;
+        }
+        Ok(reset)
+    }
+
+    async fn delete(&self, kind: Kind, id: &str) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let deleted = match kind {
+            Kind::Counter => {
+                let deleted = sqlx::query!(r#"DELETE FROM c WHERE nano_id = ?1"#, id)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected()
+                    > 0;
+                if deleted {
+                    sqlx::query!(r#"DELETE FROM c_history WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(r#"DELETE FROM c_owner WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(r#"DELETE FROM c_label WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(r#"DELETE FROM c_sketch WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(
+                        r#"INSERT OR REPLACE INTO c_deleted ( nano_id ) VALUES ( ?1 )"#,
+                        id
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                deleted
+            }
+            Kind::Gauge => {
+                let deleted = sqlx::query!(r#"DELETE FROM g WHERE nano_id = ?1"#, id)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected()
+                    > 0;
+                if deleted {
+                    sqlx::query!(r#"DELETE FROM g_history WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(r#"DELETE FROM g_owner WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(r#"DELETE FROM g_label WHERE nano_id = ?1"#, id)
+                        .execute(&mut *tx)
+                        .await?;
+                    sqlx::query!(
+                        r#"INSERT OR REPLACE INTO g_deleted ( nano_id ) VALUES ( ?1 )"#,
+                        id
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+                deleted
+            }
+        };
+        tx.commit().await?;
+        Ok(deleted)
+    }
+
+    async fn deleted(&self, kind: Kind, id: &str) -> Result<bool> {
+        Ok(match kind {
+            Kind::Counter => {
+                sqlx::query!(r#"SELECT nano_id FROM c_deleted WHERE nano_id = ?1"#, id)
+                    .fetch_optional(&self.pool)
+                    .await?
+                    .is_some()
+            }
+            Kind::Gauge => sqlx::query!(r#"SELECT nano_id FROM g_deleted WHERE nano_id = ?1"#, id)
+                .fetch_optional(&self.pool)
+                .await?
+                .is_some(),
+        })
+    }
+
+    async fn total(&self) -> Result<i64> {
+        Ok(
+            sqlx::query!(
+                r#"SELECT (SELECT count(id) FROM c) + (SELECT count(id) FROM g) as value"#
+            )
+            .fetch_one(&self.pool)
+            .await?
+            .value,
+        )
+    }
+
+    async fn highest(&self) -> Result<i64> {
+        Ok(sqlx::query!(
+            r#"SELECT value FROM c UNION SELECT value from g ORDER BY value DESC LIMIT 1"#
+        )
+        .fetch_one(&self.pool)
+        .await?
+        .value)
+    }
+
+    async fn labels(&self, kind: Kind, id: &str) -> Result<Labels> {
+        let rows = match kind {
+            Kind::Counter => {
+                sqlx::query_as!(
+                    LabelRow,
+                    r#"SELECT key, value FROM c_label WHERE nano_id = ?1"#,
+                    id
+                )
+                .fetch_all(&self.pool)
+                .await?
+            }
+            Kind::Gauge => {
+                sqlx::query_as!(
+                    LabelRow,
+                    r#"SELECT key, value FROM g_label WHERE nano_id = ?1"#,
+                    id
+                )
+                .fetch_all(&self.pool)
+                .await?
+            }
+        };
+        Ok(rows.into_iter().map(|row| (row.key, row.value)).collect())
+    }
+
+    async fn set_labels(&self, kind: Kind, id: &str, labels: &Labels) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        match kind {
+            Kind::Counter => {
+                sqlx::query!(r#"DELETE FROM c_label WHERE nano_id = ?1"#, id)
+                    .execute(&mut *tx)
+                    .await?;
+                for (key, value) in labels {
+                    sqlx::query!(
+                        r#"INSERT INTO c_label ( nano_id, key, value ) VALUES ( ?1, ?2, ?3 )"#,
+                        id,
+                        key,
+                        value
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+            Kind::Gauge => {
+                sqlx::query!(r#"DELETE FROM g_label WHERE nano_id = ?1"#, id)
+                    .execute(&mut *tx)
+                    .await?;
+                for (key, value) in labels {
+                    sqlx::query!(
+                        r#"INSERT INTO g_label ( nano_id, key, value ) VALUES ( ?1, ?2, ?3 )"#,
+                        id,
+                        key,
+                        value
+                    )
+                    .execute(&mut *tx)
+                    .await?;
+                }
+            }
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn make_unique(&self, id: &str) -> Result<()> {
+        let empty = Sketch::new();
+        let empty = empty.as_bytes();
+        sqlx::query!(
+            r#"INSERT INTO c_sketch ( nano_id, sketch ) VALUES ( ?1, ?2 )"#,
+            id,
+            empty
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn unique(&self, id: &str) -> Result<bool> {
+        Ok(
+            sqlx::query!(r#"SELECT nano_id FROM c_sketch WHERE nano_id = ?1"#, id)
+                .fetch_optional(&self.pool)
+                .await?
+                .is_some(),
+        )
+    }
+
+    async fn visit(&self, id: &str, visitor: u64) -> Result<Option<i64>> {
+        // Take the write lock up front, so concurrent visits queue up rather
+        // than failing to upgrade their read.
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        let Some(rec) = sqlx::query!(r#"SELECT sketch FROM c_sketch WHERE nano_id = ?1"#, id)
+            .fetch_optional(&mut *tx)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let mut sketch = Sketch::from_bytes(rec.sketch);
+        sketch.insert(visitor);
+        let bytes = sketch.as_bytes();
+        sqlx::query!(
+            r#"UPDATE c_sketch SET sketch = ?2 WHERE nano_id = ?1"#,
+            id,
+            bytes
+        )
+        .execute(&mut *tx)
+        .await?;
+        // Estimates can dip slightly as the sketch fills up, counters can't.
+        let current = value(&mut tx, Kind::Counter, id).await?.unwrap_or(0);
+        let by = (sketch.estimate() - current).max(0);
+        let value = increment(&mut tx, Kind::Counter, id, by).await?;
+        tx.commit().await?;
+        Ok(value)
+    }
+
+    async fn salt(&self, day: i64) -> Result<String> {
+        let mut tx = self.pool.begin().await?;
+        let fresh = nanoid!(32, &nanoid::alphabet::SAFE);
+        sqlx::query!(
+            r#"INSERT INTO salts ( day, salt ) VALUES ( ?1, ?2 ) ON CONFLICT (day) DO NOTHING"#,
+            day,
+            fresh
+        )
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query!(r#"DELETE FROM salts WHERE day < ?1"#, day)
+            .execute(&mut *tx)
+            .await?;
+        let salt = sqlx::query!(r#"SELECT salt FROM salts WHERE day = ?1"#, day)
+            .fetch_one(&mut *tx)
+            .await?
+            .salt;
+        tx.commit().await?;
+        Ok(salt)
+    }
+
+    async fn owner(&self, kind: Kind, id: &str) -> Result<Option<Owner>> {
+        let mut conn = self.pool.acquire().await?;
+        Ok(match kind {
+            Kind::Counter => sqlx::query_as!(
+                Owner,
+                r#"SELECT token_hash, protected AS "protected: bool" FROM c_owner WHERE nano_id = ?1"#,
+                id
+            )
+            .fetch_optional(&mut *conn)
+            .await?,
+            Kind::Gauge => sqlx::query_as!(
+                Owner,
+                r#"SELECT token_hash, protected AS "protected: bool" FROM g_owner WHERE nano_id = ?1"#,
+                id
+            )
+            .fetch_optional(&mut *conn)
+            .await?,
+        })
+    }
+
+    async fn claim(&self, kind: Kind, id: &str, token_hash: &str, protected: bool) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        match kind {
+            Kind::Counter => sqlx::query!(
+                r#"INSERT INTO c_owner ( nano_id, token_hash, protected ) VALUES ( ?1, ?2, ?3 )"#,
+                id,
+                token_hash,
+                protected
+            )
+            .execute(&mut *conn)
+            .await?,
+            Kind::Gauge => sqlx::query!(
+                r#"INSERT INTO g_owner ( nano_id, token_hash, protected ) VALUES ( ?1, ?2, ?3 )"#,
+                id,
+                token_hash,
+                protected
+            )
+            .execute(&mut *conn)
+            .await?,
+        };
+        Ok(())
+    }
+
+    async fn set_protected(&self, kind: Kind, id: &str, protected: bool) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+        match kind {
+            Kind::Counter => {
+                sqlx::query!(
+                    r#"UPDATE c_owner SET protected = ?2 WHERE nano_id = ?1"#,
+                    id,
+                    protected
+                )
+                .execute(&mut *conn)
+                .await?
+            }
+            Kind::Gauge => {
+                sqlx::query!(
+                    r#"UPDATE g_owner SET protected = ?2 WHERE nano_id = ?1"#,
+                    id,
+                    protected
+                )
+                .execute(&mut *conn)
+                .await?
+            }
+        };
+        Ok(())
+    }
+
+    async fn history(
+        &self,
+        kind: Kind,
+        id: &str,
+        step: Step,
+        from: i64,
+        to: i64,
+    ) -> Result<Vec<Bucket>> {
+        let step = step.seconds();
+        let from = from - from % step;
+        Ok(match kind {
+            Kind::Counter => sqlx::query!(
+                r#"SELECT bucket, delta, value FROM c_history
+                   WHERE nano_id = ?1 AND step = ?2 AND bucket BETWEEN ?3 AND ?4
+                   ORDER BY bucket LIMIT ?5"#,
+                id,
+                step,
+                from,
+                to,
+                MAX_BUCKETS
+            )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|rec| Bucket::new(rec.bucket, rec.delta, rec.value))
+            .collect(),
+            Kind::Gauge => sqlx::query!(
+                r#"SELECT bucket, delta, value FROM g_history
+                   WHERE nano_id = ?1 AND step = ?2 AND bucket BETWEEN ?3 AND ?4
+                   ORDER BY bucket LIMIT ?5"#,
+                id,
+                step,
+                from,
+                to,
+                MAX_BUCKETS
+            )
+            .fetch_all(&self.pool)
+            .await?
+            .into_iter()
+            .map(|rec| Bucket::new(rec.bucket, rec.delta, rec.value))
+            .collect(),
+        })
+    }
+
+    async fn prune_history(&self) -> Result<()> {
+        let now = Utc::now().timestamp();
+        let minutes = now - MINUTE_RETENTION;
+        let hours = now - HOUR_RETENTION;
+        sqlx::query!(
+            r#"DELETE FROM c_history
+               WHERE (step = 60 AND bucket < ?1) OR (step = 3600 AND bucket < ?2)"#,
+            minutes,
+            hours
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query!(
+            r#"DELETE FROM g_history
+               WHERE (step = 60 AND bucket < ?1) OR (step = 3600 AND bucket < ?2)"#,
+            minutes,
+            hours
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn remember(
+        &self,
+        key: &str,
+        request: &str,
+        since: i64,
+        abandoned: i64,
+    ) -> Result<Option<Remembered>> {
+        let mut tx = self.pool.begin_with("BEGIN IMMEDIATE").await?;
+        sqlx::query!(
+            r#"DELETE FROM idempotency_keys
+               WHERE key = ?1 AND ( created_at < ?2 OR ( status IS NULL AND created_at < ?3 ) )"#,
+            key,
+            since,
+            abandoned
+        )
+        .execute(&mut *tx)
+        .await?;
+        let now = Utc::now().timestamp();
+        let claimed = sqlx::query!(
+            r#"INSERT INTO idempotency_keys ( key, request, created_at ) VALUES ( ?1, ?2, ?3 )
+               ON CONFLICT (key) DO NOTHING"#,
+            key,
+            request,
+            now
+        )
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+            == 1;
+        let remembered = if claimed {
+            None
+        } else {
+            let rec = sqlx::query!(
+                r#"SELECT request, status, location, content_type, body
+                   FROM idempotency_keys WHERE key = ?1"#,
+                key
+            )
+            .fetch_one(&mut *tx)
+            .await?;
+            Some(Remembered {
+                request: rec.request,
+                response: rec.status.map(|status| Response {
+                    status: status as u16,
+                    location: rec.location,
+                    content_type: rec.content_type,
+                    body: rec.body.unwrap_or_default(),
+                }),
+            })
+        };
+        tx.commit().await?;
+        Ok(remembered)
+    }
+
+    async fn respond(&self, key: &str, response: &Response) -> Result<()> {
+        let status = i64::from(response.status);
+        sqlx::query!(
+            r#"UPDATE idempotency_keys SET status = ?2, location = ?3, content_type = ?4, body = ?5
+               WHERE key = ?1"#,
+            key,
+            status,
+            response.location,
+            response.content_type,
+            response.body
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn forget(&self, key: &str) -> Result<()> {
+        sqlx::query!(r#"DELETE FROM idempotency_keys WHERE key = ?1"#, key)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn prune_idempotency_keys(&self, before: i64) -> Result<()> {
+        sqlx::query!(
+            r#"DELETE FROM idempotency_keys WHERE created_at < ?1"#,
+            before
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn blocks(&self) -> Result<Vec<Block>> {
+        Ok(sqlx::query!(
+            r#"SELECT pattern, prefix AS "prefix: bool", created_at FROM blocked ORDER BY pattern"#
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .map(|rec| Block {
+            pattern: rec.pattern,
+            prefix: rec.prefix,
+            created_at: rec.created_at.and_utc(),
+        })
+        .collect())
+    }
+
+    async fn block(&self, pattern: &str, prefix: bool) -> Result<()> {
+        sqlx::query!(
+            r#"INSERT INTO blocked ( pattern, prefix ) VALUES ( ?1, ?2 )
+               ON CONFLICT(pattern) DO UPDATE SET prefix = excluded.prefix"#,
+            pattern,
+            prefix
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn unblock(&self, pattern: &str) -> Result<bool> {
+        Ok(
+            sqlx::query!(r#"DELETE FROM blocked WHERE pattern = ?1"#, pattern)
+                .execute(&self.pool)
+                .await?
+                .rows_affected()
+                > 0,
+        )
+    }
+}
@@ -0,0 +1,59 @@
+use actix_web::{http::header, HttpRequest};
+use nanoid::nanoid;
+use sha2::{Digest, Sha256};
+use std::env;
+
+/// The write token for a counter, as stored. Only the hash of the token is
+/// kept, the token itself is handed out once when the counter is created.
+pub struct Owner {
+    pub token_hash: String,
+    pub protected: bool,
+}
+
+/// Generates a fresh write token, returning it alongside its hash.
+pub fn generate() -> (String, String) {
+    let token = nanoid!(32, &nanoid::alphabet::SAFE);
+    let hash = hash(&token);
+    (token, hash)
+}
+
+pub fn hash(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header.
+pub fn bearer(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+        .map(str::trim)
+}
+
+/// Checks the request's bearer token against a stored token hash.
+pub fn matches(req: &HttpRequest, token_hash: &str) -> bool {
+    bearer(req).is_some_and(|token| hash(token) == token_hash)
+}
+
+/// The operator's token from `ADMIN_TOKEN`, which can manage any counter.
+/// Admin access is disabled if it isn't set.
+pub struct AdminToken(Option<String>);
+
+impl AdminToken {
+    pub fn from_env() -> Self {
+        Self(
+            env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|t| !t.is_empty())
+                .map(|t| hash(&t)),
+        )
+    }
+
+    pub fn matches(&self, req: &HttpRequest) -> bool {
+        self.0.as_deref().is_some_and(|hash| matches(req, hash))
+    }
+}
@@ -0,0 +1,46 @@
+use crate::{ratelimit, storage::Storage};
+use actix_web::{http::header, HttpRequest};
+use anyhow::Result;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::sync::Mutex;
+
+/// Caches today's salt for hashing visitors, so it's only read from the
+/// database once a day.
+#[derive(Default)]
+pub struct Salts(Mutex<Option<(i64, String)>>);
+
+impl Salts {
+    pub async fn today(&self, storage: &dyn Storage) -> Result<String> {
+        let day = Utc::now().timestamp().div_euclid(24 * 60 * 60);
+        if let Some((cached, salt)) = &*self.0.lock().unwrap() {
+            if *cached == day {
+                return Ok(salt.clone());
+            }
+        }
+        let salt = storage.salt(day).await?;
+        *self.0.lock().unwrap() = Some((day, salt.clone()));
+        Ok(salt)
+    }
+}
+
+/// Identifies the client for the day `salt` belongs to, by hashing it with
+/// their IP and User-Agent. Neither is kept, and once the salt is gone the
+/// hash can't be tied back to them. The IP is found as for rate limiting, so
+/// clients can't pass as new visitors by making up forwarding headers.
+pub fn visitor(req: &HttpRequest, salt: &str) -> u64 {
+    let ip = ratelimit::client_addr(req).unwrap_or_default();
+    let user_agent = req
+        .headers()
+        .get(header::USER_AGENT)
+        .map(|ua| ua.as_bytes())
+        .unwrap_or_default();
+    let hash = Sha256::new()
+        .chain_update(salt)
+        .chain_update([0])
+        .chain_update(ip)
+        .chain_update([0])
+        .chain_update(user_agent)
+        .finalize();
+    u64::from_be_bytes(hash[..8].try_into().unwrap())
+}
@@ -0,0 +1,262 @@
+use crate::{
+    batch::{self, Item, Outcome, Type},
+    blocklist::Blocklist,
+    events::{Events, Received, Subscription},
+    ratelimit::{self, RateLimiter},
+    storage::{Kind, Op, Storage},
+    Counter, CounterLike, Gauge,
+};
+use actix_web::{
+    get, rt,
+    rt::time::{timeout, Instant},
+    web::{Data, Payload},
+    Error, HttpRequest, HttpResponse,
+};
+use actix_ws::{Message, Session};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, collections::HashSet, rc::Rc, sync::Arc, time::Duration};
+
+/// Upper bound on counters and gauges a single socket can subscribe to.
+const MAX_SUBSCRIPTIONS: usize = 100;
+
+/// How often an idle socket is pinged, so proxies keep it open and closed
+/// connections are noticed.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+type Subscriptions = Rc<RefCell<HashSet<(Kind, String)>>>;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Action {
+    Subscribe,
+    Unsubscribe,
+}
+
+#[derive(Deserialize)]
+struct SubscriptionRequest {
+    op: Action,
+    #[serde(rename = "type")]
+    kind: Type,
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Request {
+    Subscription(SubscriptionRequest),
+    Op(Item),
+}
+
+#[derive(Serialize)]
+struct Reply<'a> {
+    #[serde(rename = "type")]
+    kind: Type,
+    id: &'a str,
+    /// Missing when acknowledging an unsubscribe.
+    #[serde(flatten)]
+    outcome: Option<Outcome>,
+}
+
+/// A WebSocket that can subscribe to changes of many counters and gauges at
+/// once, and run the same operations as a batch.
+#[get("/_ws")]
+async fn get_ws(
+    req: HttpRequest,
+    body: Payload,
+    events: Data<Events>,
+    blocklist: Data<Blocklist>,
+    limiter: Data<RateLimiter>,
+    storage: Data<dyn Storage>,
+) -> Result<HttpResponse, Error> {
+    let Some(subscription) = events.subscribe() else {
+        return Ok(HttpResponse::ServiceUnavailable().body("too many connections"));
+    };
+    let (response, mut session, mut messages) = actix_ws::handle(&req, body)?;
+    let subscriptions = Subscriptions::default();
+    let forwarder = rt::spawn(forward(
+        subscription,
+        session.clone(),
+        subscriptions.clone(),
+        storage.clone().into_inner(),
+    ));
+
+    rt::spawn(async move {
+        while let Some(Ok(message)) = messages.recv().await {
+            let sent = match message {
+                Message::Text(text) => {
+                    let reply = match serde_json::from_str(&text) {
+                        Ok(request) => {
+                            handle(
+                                request,
+                                &req,
+                                &subscriptions,
+                                &blocklist,
+                                &limiter,
+                                storage.get_ref(),
+                            )
+                            .await
+                        }
+                        Err(_) => r#"{"error":"invalid message"}"#.to_owned(),
+                    };
+                    session.text(reply).await
+                }
+                Message::Ping(bytes) => session.pong(&bytes).await,
+                Message::Close(_) => break,
+                _ => Ok(()),
+            };
+            if sent.is_err() {
+                break;
+            }
+        }
+        forwarder.abort();
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+/// Runs a single message from the client, answering with the JSON reply.
+/// Each change is rate limited like a request of its own.
+async fn handle(
+    request: Request,
+    req: &HttpRequest,
+    subscriptions: &Subscriptions,
+    blocklist: &Blocklist,
+    limiter: &RateLimiter,
+    storage: &dyn Storage,
+) -> String {
+    let (kind, id, outcome) = match request {
+        Request::Subscription(request) => {
+            let outcome = subscribe(&request, subscriptions, blocklist, storage).await;
+            (request.kind, request.id, outcome)
+        }
+        Request::Op(item) => {
+            let client = ratelimit::client_addr(req);
+            let checked =
+                batch::check(&item, req, client.as_deref(), blocklist, limiter, storage).await;
+            let outcome = match checked {
+                Ok((kind, op)) => match batch::run(kind, &item.id, op, storage).await {
+                    Ok(value) => batch::outcome(kind, &item.id, op, value, storage).await,
+                    Err(_) => Outcome::Error {
+                        error: "internal error",
+                    },
+                },
+                Err(error) => Outcome::Error { error },
+            };
+            (item.kind, item.id, Some(outcome))
+        }
+    };
+    serde_json::to_string(&Reply {
+        kind,
+        id: &id,
+        outcome,
+    })
+    .unwrap_or_default()
+}
+
+/// Starts or stops sending changes of a counter or gauge down the socket.
+/// Subscribing answers with the current value.
+async fn subscribe(
+    request: &SubscriptionRequest,
+    subscriptions: &Subscriptions,
+    blocklist: &Blocklist,
+    storage: &dyn Storage,
+) -> Option<Outcome> {
+    let kind = Kind::from(request.kind);
+    let valid = match kind {
+        Kind::Counter => Counter::valid_id(&request.id),
+        Kind::Gauge => Gauge::valid_id(&request.id),
+    };
+    if !valid {
+        return Some(Outcome::Error {
+            error: "invalid id",
+        });
+    }
+    let key = (kind, request.id.clone());
+    if let Action::Unsubscribe = request.op {
+        subscriptions.borrow_mut().remove(&key);
+        return None;
+    }
+    if blocklist.is_blocked(&request.id) {
+        return Some(Outcome::Error { error: "gone" });
+    }
+    if subscriptions.borrow().len() >= MAX_SUBSCRIPTIONS {
+        return Some(Outcome::Error {
+            error: "too many subscriptions",
+        });
+    }
+    let Ok(value) = batch::run(kind, &request.id, Op::Get, storage).await else {
+        return Some(Outcome::Error {
+            error: "internal error",
+        });
+    };
+    let outcome = batch::outcome(kind, &request.id, Op::Get, value, storage).await;
+    if let Outcome::Value { .. } = outcome {
+        subscriptions.borrow_mut().insert(key);
+    }
+    Some(outcome)
+}
+
+/// Sends every change the socket is subscribed to, pinging whenever it's
+/// quiet. Deleted counters and gauges are answered with `gone` and dropped
+/// from the subscriptions.
+async fn forward(
+    mut subscription: Subscription,
+    mut session: Session,
+    subscriptions: Subscriptions,
+    storage: Arc<dyn Storage>,
+) {
+    let mut keep_alive = Instant::now() + KEEP_ALIVE;
+    loop {
+        let wait = keep_alive.saturating_duration_since(Instant::now());
+        let changes = match timeout(wait, subscription.recv()).await {
+            Ok(Some(Received::Change(change))) => {
+                let key = (change.kind, change.id);
+                if !subscriptions.borrow().contains(&key) {
+                    continue;
+                }
+                vec![(key, change.value)]
+            }
+            // Changes were missed, so everything subscribed to is read afresh.
+            Ok(Some(Received::Lagged)) => {
+                let keys: Vec<_> = subscriptions.borrow().iter().cloned().collect();
+                let mut changes = Vec::with_capacity(keys.len());
+                for (kind, id) in keys {
+                    if let Ok(record) = storage.get(kind, &id).await {
+                        changes.push(((kind, id), record.map(|record| record.value)));
+                    }
+                }
+                changes
+            }
+            Ok(None) => return,
+            Err(_) => {
+                if session.ping(b"").await.is_err() {
+                    return;
+                }
+                keep_alive = Instant::now() + KEEP_ALIVE;
+                continue;
+            }
+        };
+        for (key, value) in changes {
+            let outcome = match value {
+                Some(value) => Outcome::Value { value },
+                None => {
+                    subscriptions.borrow_mut().remove(&key);
+                    Outcome::Error { error: "gone" }
+                }
+            };
+            let reply = Reply {
+                kind: key.0.into(),
+                id: &key.1,
+                outcome: Some(outcome),
+            };
+            let sent = session
+                .text(serde_json::to_string(&reply).unwrap_or_default())
+                .await;
+            if sent.is_err() {
+                return;
+            }
+        }
+        keep_alive = Instant::now() + KEEP_ALIVE;
+    }
+}